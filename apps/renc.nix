@@ -19,12 +19,19 @@ let
       + " "
       + (pkgs.writeTextFile {
         name = "vaultix-material";
-        text = builtins.toJSON v.config.vaultix;
+        text = builtins.toJSON v.config.vaultix-debug;
       })
     ) (attrValues nodes)
   );
 
-  rencCmds = "${bin} ${profilesArgs} renc --identity ${identity} --cache ${cache}";
+  # a running agent holds the unlocked identity, ask it instead
+  rencCmds = ''
+    sock="''${VAULTIX_AGENT_SOCK:-''${XDG_RUNTIME_DIR:-}/vaultix/agent.sock}"
+    if [ -S "$sock" ]; then
+      exec ${bin} ${profilesArgs} renc --cache ${cache} "$@"
+    fi
+    exec ${bin} ${profilesArgs} renc --identity ${identity} --cache ${cache} "$@"
+  '';
 
 in
 writeShellScriptBin "renc" rencCmds
@@ -64,6 +64,17 @@ in
                 to decrypt all secrets.
               '';
             };
+            cacheRecipients = mkOption {
+              type = with types; listOf str;
+              default = [ ];
+              example = [
+                "age1qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqs3290gq"
+              ];
+              description = ''
+                Recipients added to re-encrypted cache files of every host, e.g. an
+                offline recovery key. Default of `vaultix.settings.extraRecipients`.
+              '';
+            };
             app = mkOption {
               type = types.lazyAttrsOf (types.lazyAttrsOf types.package);
               default = lib.mapAttrs (
@@ -8,6 +8,14 @@
 let
   inherit (lib)
     all
+    any
+    attrNames
+    attrValues
+    elem
+    mapAttrs
+    mapAttrs'
+    mkMerge
+    nameValuePair
     types
     mkOption
     isPath
@@ -134,6 +142,80 @@ let
           Host identifier
         '';
       };
+      extraRecipients = mkOption {
+        type = types.listOf types.str;
+        default = self.vaultix.cacheRecipients or [ ];
+        defaultText = literalExpression "flake.vaultix.cacheRecipients";
+        example = [
+          "age1qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqs3290gq"
+        ];
+        description = ''
+          Recipients added to every re-encrypted cache file of this host besides
+          host public key, e.g. an offline recovery key. Changing it re-encrypts
+          all secrets of this host.
+        '';
+      };
+
+      secretsFs = {
+        type = mkOption {
+          type = types.enum [
+            "ramfs"
+            "tmpfs"
+          ];
+          default = "ramfs";
+          description = ''
+            Filesystem mounted on {option}`decryptedMountPoint`. ramfs has no
+            size limit, tmpfs is capped by `size` and mounted with `noswap`,
+            which needs linux 6.4 or later.
+          '';
+        };
+        size = mkOption {
+          type = types.nullOr types.str;
+          default = null;
+          example = "16M";
+          description = ''
+            tmpfs `size=` option, absolute or a percentage of memory.
+          '';
+        };
+        noswap = mkOption {
+          type = types.bool;
+          default = true;
+          description = ''
+            Mount tmpfs with `noswap`. Deploy refuses tmpfs without it.
+          '';
+        };
+        flags = mkOption {
+          type = types.listOf (
+            types.enum [
+              "nosuid"
+              "nodev"
+              "noexec"
+              "relatime"
+              "noatime"
+              "nodiratime"
+            ]
+          );
+          default = [
+            "nosuid"
+            "nodev"
+            "noexec"
+            "relatime"
+          ];
+          description = ''
+            Mount flags, `nosuid` and `nodev` are required.
+          '';
+        };
+      };
+
+      strictOwner = mkOption {
+        type = types.bool;
+        default = false;
+        description = ''
+          Fail the whole deploy when any owner, group or ACL entry can't be
+          resolved, instead of deferring early items or skipping the item.
+        '';
+      };
+
       hostPubkey = mkOption {
         type = with types; coercedTo path (x: if isPath x then readFile x else x) str;
         example = literalExpression "./secrets/host1.pub";
@@ -177,14 +259,100 @@ in
       type = types.listOf types.str;
       default = [ ];
       description = ''
-        List of id of items needed before user init
+        List of id of items needed before user init. Shorthand of setting
+        their `phase` to `"early"`.
+      '';
+    };
+
+    serve = {
+      enable = mkOption {
+        type = types.bool;
+        default = false;
+        description = ''
+          Run `vaultix serve`, handing out secrets with `serve` policy on a
+          unix socket instead of writing them to disk.
+        '';
+      };
+      socket = mkOption {
+        type = types.str;
+        default = "/run/vaultix-serve.sock";
+        description = ''
+          Socket path, clients read it with `vaultix fetch <id> --socket <path>`.
+        '';
+      };
+      auditLog = mkOption {
+        type = types.nullOr types.str;
+        default = "/var/log/vaultix/serve-audit.log";
+        description = ''
+          File every request is appended to as a json line, granted or not.
+          Null for the journal.
+        '';
+      };
+    };
+
+    phases = mkOption {
+      type = types.attrsOf (
+        types.submodule (submod: {
+          options = {
+            link = mkOption {
+              type = types.str;
+              default = "${cfg.settings.decryptedDir}-${submod.config._module.args.name}";
+              defaultText = literalExpression ''"''${cfg.settings.decryptedDir}-''${name}"'';
+              description = ''
+                Where the generation dir of this phase is linked to.
+              '';
+            };
+            after = mkOption {
+              type = types.listOf types.str;
+              default = [ ];
+              example = [ "home-manager-alice.service" ];
+              description = ''
+                Units to wait for, besides `vaultix-activate.service`.
+              '';
+            };
+            requiresMountsFor = mkOption {
+              type = types.listOf types.str;
+              default = [ ];
+              example = [ "/home" ];
+              description = ''
+                Deploy once these paths are mounted.
+              '';
+            };
+            wantedBy = mkOption {
+              type = types.listOf types.str;
+              default = [ "multi-user.target" ];
+              description = ''
+                Empty for deploying on demand, with
+                `systemctl start vaultix-activate-<name>`.
+              '';
+            };
+          };
+        })
+      );
+      default = { };
+      description = ''
+        Custom deploy phases besides built-in `early` (before users, linked
+        to `decryptedDirForUser`) and `default` (linked to `decryptedDir`).
+        Each runs as its own service and gets its own generation dir.
       '';
     };
   };
 
   options.vaultix-debug = mkOption {
     type = types.unspecified;
-    default = cfg;
+    # profile consumed by vaultix, bump `version` with src/profile/schema.rs
+    default = {
+      version = 2;
+      inherit (cfg)
+        secrets
+        templates
+        placeholder
+        ;
+      phases = mapAttrs (_: p: { inherit (p) link; }) cfg.phases;
+      settings = cfg.settings // {
+        hostKeys = map (k: { inherit (k) path type; }) cfg.settings.hostKeys;
+      };
+    };
   };
 
   config =
@@ -196,11 +364,13 @@ in
           text = builtins.toJSON partial;
         };
 
-      profile = mkProfile cfg;
+      profile = mkProfile config.vaultix-debug;
+
+      items = attrValues cfg.secrets ++ attrValues cfg.templates;
 
       checkRencSecsReport =
         pkgs.runCommandNoCCLocal "secret-check-report" { }
-          "${lib.getExe cfg.package} -p ${mkProfile cfg} check > $out";
+          "${lib.getExe cfg.package} -p ${profile} check > $out";
     in
     mkIf sysusers (
       let
@@ -209,41 +379,95 @@ in
           ("CHECK_RESULT=" + checkRencSecsReport)
         ];
       in
-      {
-
-        systemd.services.vaultix-activate = {
-          wantedBy = [ "sysinit.target" ];
-          after = [ "systemd-sysusers.service" ];
-          unitConfig.DefaultDependencies = "no";
-          serviceConfig = {
-            Type = "oneshot";
-            Environment = deployRequisites;
-            ExecStart = "${lib.getExe cfg.package} -p ${profile} deploy";
-            RemainAfterExit = true;
+      mkMerge [
+        {
+          systemd.services.vaultix-activate = {
+            wantedBy = [ "sysinit.target" ];
+            after = [
+              "systemd-sysusers.service"
+              "userborn.service"
+            ];
+            unitConfig.DefaultDependencies = "no";
+            serviceConfig = {
+              Type = "oneshot";
+              Environment = deployRequisites;
+              ExecStart = "${lib.getExe cfg.package} -p ${profile} deploy";
+              RemainAfterExit = true;
+            };
           };
-        };
 
-        systemd.services.vaultix-activate-before-user = mkIf (cfg.beforeUserborn != [ ]) {
-          wantedBy = [ "systemd-sysusers.service" ];
-          before = [ "systemd-sysusers.service" ];
-          unitConfig.DefaultDependencies = "no";
+          systemd.services.vaultix-activate-before-user = mkIf (any (i: i.phase == "early") items) {
+            wantedBy = [ "systemd-sysusers.service" ];
+            before = [ "systemd-sysusers.service" ];
+            unitConfig.DefaultDependencies = "no";
 
-          serviceConfig = {
-            Type = "oneshot";
-            Environment = deployRequisites;
-            ExecStart = "${lib.getExe cfg.package} -p ${profile} deploy --early";
-            RemainAfterExit = true;
+            serviceConfig = {
+              Type = "oneshot";
+              Environment = deployRequisites;
+              ExecStart = "${lib.getExe cfg.package} -p ${profile} deploy --early";
+              RemainAfterExit = true;
+            };
           };
-        };
 
-        assertions = [
-          {
-            assertion = all (b: b) (
-              map (i: hasAttr i cfg.templates || hasAttr i cfg.secrets) cfg.beforeUserborn
-            );
-            message = "one or more element of `beforeUserborn` not found in either templates or secrets.";
-          }
-        ];
-      }
+          assertions = [
+            {
+              assertion = all (b: b) (
+                map (i: hasAttr i cfg.templates || hasAttr i cfg.secrets) cfg.beforeUserborn
+              );
+              message = "one or more element of `beforeUserborn` not found in either templates or secrets.";
+            }
+            {
+              assertion = all (i: elem i.phase ([ "early" "default" ] ++ attrNames cfg.phases)) items;
+              message = "one or more secrets or templates use a phase not declared in `vaultix.phases`.";
+            }
+            {
+              assertion = !(cfg.phases ? early || cfg.phases ? default);
+              message = "`early` and `default` are built-in phases, not declarable in `vaultix.phases`.";
+            }
+            {
+              assertion = cfg.settings.secretsFs.type == "tmpfs" || all (i: i.acl == [ ]) items;
+              message = "`acl` of secrets or templates needs `vaultix.settings.secretsFs.type = \"tmpfs\"`, ramfs has no ACL support.";
+            }
+            {
+              assertion = cfg.serve.enable || all (s: s.serve == null) (attrValues cfg.secrets);
+              message = "secrets with `serve` policy need `vaultix.serve.enable`.";
+            }
+            {
+              assertion = cfg.settings.secretsFs.type == "tmpfs" || cfg.settings.secretsFs.size == null;
+              message = "`vaultix.settings.secretsFs.size` only applies to tmpfs.";
+            }
+          ];
+        }
+        (mkIf cfg.serve.enable {
+          systemd.services.vaultix-serve = {
+            wantedBy = [ "multi-user.target" ];
+            after = [ "vaultix-activate.service" ];
+            serviceConfig = {
+              Environment = deployRequisites;
+              ExecStart =
+                "${lib.getExe cfg.package} -p ${profile} serve --socket ${cfg.serve.socket}"
+                + lib.optionalString (cfg.serve.auditLog != null) " --audit-log ${cfg.serve.auditLog}";
+              LogsDirectory = "vaultix";
+              Restart = "on-failure";
+            };
+          };
+        })
+        {
+          systemd.services = mapAttrs' (
+            name: p:
+            nameValuePair "vaultix-activate-${name}" {
+              inherit (p) wantedBy;
+              after = [ "vaultix-activate.service" ] ++ p.after;
+              unitConfig.RequiresMountsFor = p.requiresMountsFor;
+              serviceConfig = {
+                Type = "oneshot";
+                Environment = deployRequisites;
+                ExecStart = "${lib.getExe cfg.package} -p ${profile} deploy --phase ${name}";
+                RemainAfterExit = true;
+              };
+            }
+          ) cfg.phases;
+        }
+      ]
     );
 }
@@ -9,6 +9,7 @@ let
   inherit (lib)
     types
     elem
+    mapAttrs
     mkOption
     literalExpression
     ;
@@ -39,10 +40,14 @@ in
       path = mkOption {
         type = types.str;
         default =
-          if elem submod.config._module.args.name cfg.beforeUserborn then
-            "${cfg.settings.decryptedDirForUser}/${submod.config.name}"
-          else
-            "${cfg.settings.decryptedDir}/${submod.config.name}";
+          let
+            links = {
+              early = cfg.settings.decryptedDirForUser;
+              default = cfg.settings.decryptedDir;
+            }
+            // mapAttrs (_: p: p.link) cfg.phases;
+          in
+          "${links.${submod.config.phase}}/${submod.config.name}";
         defaultText = literalExpression ''
           "''${cfg.settings.decryptedDir}/''${config.name}"
         '';
@@ -50,6 +55,14 @@ in
           Path where the decrypted secret is installed.
         '';
       };
+      phase = mkOption {
+        type = types.str;
+        default = if elem submod.config._module.args.name cfg.beforeUserborn then "early" else "default";
+        defaultText = literalExpression ''if elem name cfg.beforeUserborn then "early" else "default"'';
+        description = ''
+          Deploy phase of this secret, `early`, `default` or one of {option}`vaultix.phases`.
+        '';
+      };
       mode = mkOption {
         type = types.str;
         default = "0400";
@@ -61,7 +74,7 @@ in
         type = types.str;
         default = "root";
         description = ''
-          User of the decrypted secret.
+          User of the decrypted secret, name or numeric uid.
         '';
       };
       group = mkOption {
@@ -71,7 +84,127 @@ in
           users.''${config.owner}.group or "root"
         '';
         description = ''
-          Group of the decrypted secret.
+          Group of the decrypted secret, name or numeric gid.
+        '';
+      };
+      acl = mkOption {
+        type = types.listOf types.str;
+        default = [ ];
+        example = [
+          "user:nginx:r"
+          "group:1001:r"
+        ];
+        description = ''
+          Extra POSIX ACL entries of the decrypted secret, `user:<name or uid>:<perm>`
+          or `group:<name or gid>:<perm>`. Requires a secrets filesystem
+          with ACL support.
+        '';
+      };
+      keyring = mkOption {
+        type = types.nullOr (
+          types.submodule {
+            options = {
+              type = mkOption {
+                type = types.enum [
+                  "user"
+                  "logon"
+                ];
+                default = "user";
+                description = ''
+                  Key type. `logon` keys could only be used by kernel, never read back.
+                '';
+              };
+              keyring = mkOption {
+                type = types.enum [
+                  "user"
+                  "persistent"
+                ];
+                default = "user";
+                description = ''
+                  `user` keyring of root, which deploys, or `persistent`
+                  keyring of the owner. The session keyring of deploy is
+                  gone once it exits, so isn't offered.
+                '';
+              };
+              name = mkOption {
+                type = types.nullOr types.str;
+                default = null;
+                description = ''
+                  Keyring of this name nested in {option}`keyring`, created if missing.
+                '';
+              };
+              description = mkOption {
+                type = types.str;
+                default = "vaultix:${submod.config.id}";
+                defaultText = literalExpression ''"vaultix:''${config.id}"'';
+                description = ''
+                  Key description. `logon` keys require a `<service>:` prefix.
+                '';
+              };
+              perm = mkOption {
+                type = types.str;
+                default = "3f0b0000";
+                description = ''
+                  Key permission mask in hex, as `keyctl setperm` takes.
+                '';
+              };
+              timeout = mkOption {
+                type = types.ints.unsigned;
+                default = 0;
+                description = ''
+                  Seconds until the key expires, 0 for never.
+                '';
+              };
+            };
+          }
+        );
+        default = null;
+        example = literalExpression ''
+          {
+            type = "logon";
+            description = "cryptsetup:data";
+          }
+        '';
+        description = ''
+          Add the secret to kernel keyring instead of writing a file, owned
+          by {option}`owner` and {option}`group`. `path`, `mode` and `acl`
+          don't apply.
+        '';
+      };
+      serve = mkOption {
+        type = types.nullOr (
+          types.submodule {
+            options = {
+              users = mkOption {
+                type = types.listOf types.str;
+                default = [ ];
+                description = "Users allowed besides {option}`owner`, name or numeric uid.";
+              };
+              groups = mkOption {
+                type = types.listOf types.str;
+                default = [ ];
+                description = ''
+                  Groups allowed besides {option}`group`, name or numeric gid.
+                  Matched with primary and supplementary groups of the
+                  requesting process.
+                '';
+              };
+              executables = mkOption {
+                type = types.listOf types.str;
+                default = [ ];
+                example = literalExpression ''[ "''${pkgs.nginx}/bin/nginx" ]'';
+                description = ''
+                  Requesting process must be running one of these, any if empty.
+                '';
+              };
+            };
+          }
+        );
+        default = null;
+        description = ''
+          Never write the secret to disk, hold it in memory of
+          `vaultix serve` and hand it out to processes this allows.
+          Requires {option}`vaultix.serve.enable`.
         '';
       };
     };
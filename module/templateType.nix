@@ -9,6 +9,7 @@ let
   inherit (lib)
     types
     elem
+    mapAttrs
     mkOption
     literalExpression
     mkEnableOption
@@ -40,10 +41,14 @@ in
       path = mkOption {
         type = types.str;
         default =
-          if elem submod.config._module.args.name cfg.beforeUserborn then
-            "${cfg.settings.decryptedDirForUser}/${submod.config.name}"
-          else
-            "${cfg.settings.decryptedDir}/${submod.config.name}";
+          let
+            links = {
+              early = cfg.settings.decryptedDirForUser;
+              default = cfg.settings.decryptedDir;
+            }
+            // mapAttrs (_: p: p.link) cfg.phases;
+          in
+          "${links.${submod.config.phase}}/${submod.config.name}";
 
         defaultText = literalExpression ''
           if elem submod.config._module.args.name cfg.needbyUser then
@@ -55,6 +60,14 @@ in
           Path where the built template is installed.
         '';
       };
+      phase = mkOption {
+        type = types.str;
+        default = if elem submod.config._module.args.name cfg.beforeUserborn then "early" else "default";
+        defaultText = literalExpression ''if elem name cfg.beforeUserborn then "early" else "default"'';
+        description = ''
+          Deploy phase of this template, `early`, `default` or one of {option}`vaultix.phases`.
+        '';
+      };
       mode = mkOption {
         type = types.str;
         default = "0400";
@@ -66,7 +79,7 @@ in
         type = types.str;
         default = "root";
         description = ''
-          User of the built template.
+          User of the built template, name or numeric uid.
         '';
       };
       group = mkOption {
@@ -76,7 +89,20 @@ in
           users.''${config.owner}.group or "root"
         '';
         description = ''
-          Group of the built template.
+          Group of the built template, name or numeric gid.
+        '';
+      };
+      acl = mkOption {
+        type = types.listOf types.str;
+        default = [ ];
+        example = [
+          "user:nginx:r"
+          "group:1001:r"
+        ];
+        description = ''
+          Extra POSIX ACL entries of the built template, `user:<name or uid>:<perm>`
+          or `group:<name or gid>:<perm>`. Requires a secrets filesystem
+          with ACL support.
         '';
       };
     };
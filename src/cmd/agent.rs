@@ -0,0 +1,235 @@
+use std::{
+    fs::{self, DirBuilder},
+    io::ErrorKind,
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::PathBuf,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use age::Identity;
+use age_core::format::Stanza;
+use eyre::{Context, ContextCompat, Result, bail, eyre};
+use log::{debug, info, warn};
+
+use crate::{
+    parser::identity::{ParsedIdentity, RawIdentity},
+    util::agent::{
+        MAX_CONNECTIONS, Request, Response, SOCKET_ENV, Slots, peer_uid, read_message, socket_path,
+        write_message,
+    },
+};
+
+use super::AgentSubCmd;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+fn prepare_socket(path: &PathBuf) -> Result<UnixListener> {
+    let uid = unsafe { libc::getuid() };
+    let dir = path
+        .parent()
+        .with_context(|| eyre!("socket path has no parent"))?;
+
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .wrap_err_with(|| eyre!("create agent socket dir error"))?;
+    let meta = fs::metadata(dir)?;
+    if meta.uid() != uid || meta.mode() & 0o077 != 0 {
+        bail!(
+            "agent socket dir {} must be owned by uid {} and not accessible by others",
+            dir.display(),
+            uid
+        );
+    }
+
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            bail!("agent already running at {}", path.display());
+        }
+        debug!("removing stale socket {}", path.display());
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .wrap_err_with(|| eyre!("bind agent socket error: {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// stanzas read off a connection, answered by the thread holding identity
+type Job = (Vec<Stanza>, mpsc::Sender<Response>);
+
+/// connection side, only the unwrap itself waits for identity
+fn handle(stream: UnixStream, own: u32, jobs: &mpsc::Sender<Job>) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let peer = peer_uid(&stream)?;
+    if peer != own {
+        bail!("refused connection from uid {}", peer);
+    }
+
+    let resp = match read_message::<Request>(&stream)? {
+        Request::Unwrap(s) => match s
+            .into_iter()
+            .map(Stanza::try_from)
+            .collect::<Result<Vec<Stanza>, _>>()
+        {
+            Ok(stanzas) => {
+                let (tx, rx) = mpsc::channel();
+                jobs.send((stanzas, tx))
+                    .map_err(|_| eyre!("agent shutting down"))?;
+                rx.recv().map_err(|_| eyre!("agent shutting down"))?
+            }
+            Err(e) => Response::Error(format!("malformed stanza: {}", e)),
+        },
+    };
+    write_message(&stream, &resp)?;
+    Ok(())
+}
+
+fn answer(identity: &dyn Identity, (stanzas, reply): Job) {
+    // connection may be gone already
+    let _ = reply.send(Response::from_unwrapped(identity.unwrap_stanzas(&stanzas)));
+}
+
+/**
+Unlock identities once and serve decryption on a user-only unix socket
+
+Clients send recipient stanzas of age header, agent replies file key if
+any identity unwraps it. Connections are read concurrently, up to
+[`MAX_CONNECTIONS`], so an idle client never holds up others. Exits after
+`timeout` seconds without request.
+*/
+pub fn agent(arg: &AgentSubCmd) -> Result<()> {
+    let AgentSubCmd {
+        identity,
+        socket,
+        timeout,
+    } = arg;
+
+    if identity.is_empty() {
+        bail!("agent requires identity to unlock");
+    }
+    let ParsedIdentity {
+        identity,
+        recipients: _,
+    } = RawIdentity::from(identity.clone()).try_into()?;
+
+    let path = socket.as_ref().map_or_else(socket_path, PathBuf::from);
+    let listener = prepare_socket(&path)?;
+    listener.set_nonblocking(true)?;
+
+    info!("agent listening on {}", path.display());
+    info!("export {}={}", SOCKET_ENV, path.display());
+
+    let timeout = Duration::from_secs(*timeout);
+    let own = unsafe { libc::getuid() };
+    let slots = Slots::default();
+    let (jobs, queue) = mpsc::channel::<Job>();
+    let res = thread::scope(|s| {
+        let mut last_active = Instant::now();
+        let res = loop {
+            // a flood of connections never starves requests already read
+            while let Ok(job) = queue.try_recv() {
+                last_active = Instant::now();
+                answer(identity.as_ref(), job);
+            }
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    last_active = Instant::now();
+                    let Some(slot) = slots.take() else {
+                        warn!("{} connections in progress, refused one", MAX_CONNECTIONS);
+                        continue;
+                    };
+                    let jobs = jobs.clone();
+                    s.spawn(move || {
+                        if let Err(e) = handle(stream, own, &jobs) {
+                            warn!("{}", e);
+                        }
+                        drop(slot);
+                    });
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if slots.idle() && last_active.elapsed() > timeout {
+                        info!("idle for {}s, exiting", timeout.as_secs());
+                        break Ok(());
+                    }
+                    // unwrap requested ones while waiting for new connection
+                    match queue.recv_timeout(POLL_INTERVAL) {
+                        Ok(job) => {
+                            last_active = Instant::now();
+                            answer(identity.as_ref(), job);
+                        }
+                        Err(RecvTimeoutError::Timeout) => (),
+                        Err(RecvTimeoutError::Disconnected) => unreachable!("sender held"),
+                    }
+                }
+                Err(e) => break Err(e).wrap_err_with(|| eyre!("accept connection error")),
+            }
+        };
+        // pending connections see it closed
+        drop(queue);
+        res
+    });
+
+    let _ = fs::remove_file(&path);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::{Recipient, x25519};
+    use age_core::format::FileKey;
+    use base64::{Engine, engine::general_purpose::STANDARD};
+
+    use crate::util::agent::WireStanza;
+
+    fn request(stanzas: &[Stanza]) -> Request {
+        Request::Unwrap(stanzas.iter().map(WireStanza::from).collect())
+    }
+
+    #[test]
+    fn unwrap_through_socket() {
+        let identity = x25519::Identity::generate();
+        let file_key = FileKey::new(Box::new([7; 16]));
+        let (stanzas, _) = identity.to_public().wrap_file_key(&file_key).unwrap();
+        let other = x25519::Identity::generate().to_public();
+        let (foreign, _) = other.wrap_file_key(&file_key).unwrap();
+
+        let own = unsafe { libc::getuid() };
+        let (jobs, queue) = mpsc::channel();
+        for (req, expect) in [(&stanzas, Some([7; 16])), (&foreign, None)] {
+            let (client, server) = UnixStream::pair().unwrap();
+            thread::scope(|s| {
+                let jobs = jobs.clone();
+                s.spawn(move || handle(server, own, &jobs).unwrap());
+                write_message(&client, &request(req)).unwrap();
+                answer(&identity, queue.recv().unwrap());
+                match (read_message::<Response>(&client).unwrap(), expect) {
+                    (Response::FileKey(k), Some(e)) => assert_eq!(STANDARD.decode(k).unwrap(), e),
+                    (Response::NoMatch, None) => (),
+                    (r, _) => panic!("unexpected {:?}", r),
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn refuse_other_uid() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let (jobs, queue) = mpsc::channel();
+        let own = unsafe { libc::getuid() }.wrapping_add(1);
+        assert!(handle(server, own, &jobs).is_err());
+        assert!(queue.try_recv().is_err());
+        // closed without an answer
+        assert!(read_message::<Response>(&client).is_err());
+    }
+}
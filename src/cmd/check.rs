@@ -1,37 +1,132 @@
-use eyre::{Context, ContextCompat, Result, eyre};
-use log::debug;
+use std::{iter, path::PathBuf};
 
-use crate::util::secmap::{RencBuilder, RencCtx};
+use eyre::{ContextCompat, bail, eyre};
+use log::{debug, error};
+
+use crate::{
+    error::{Error, Result},
+    parser::{
+        age_header::parse_stanzas,
+        recipient::{RawRecip, StanzaKind},
+    },
+    profile::Profile,
+    util::{
+        rotation::RotationRecord,
+        secmap::{GetSec, RencBuilder, RencCtx},
+    },
+};
 
 use super::renc::CompleteProfile;
 
 impl CompleteProfile<'_> {
+    /// every profile is checked against its own cache
     pub fn check(&self) -> Result<()> {
-        let profile = self
-            .inner_ref()
-            .first()
-            .with_context(|| eyre::eyre!("deploy must only one host"))?;
-
-        let ctx = RencCtx::create(self)?;
-
-        let inst = RencBuilder::create(self)
-            .build_instore()
-            .renced_stored(&ctx, profile.settings.cache_in_store.clone().into())
-            .inner();
-
-        inst.values().try_for_each(|p| {
-            debug!("checking in-store path: {}", p.path.display());
-            if !p.path.exists() {
-                return Err(eyre!(
-                    "See https://milieuim.github.io/vaultix/nix-apps.html#renc"
-                ))
-                .wrap_err_with(|| eyre!("Please run renc and add new production to git"))
-                .wrap_err_with(|| eyre!("Forget adding it to git?"))
-                .wrap_err_with(|| {
-                    eyre::eyre!("secrets haven't been re-encrypted: {}", p.path.display())
-                });
+        if self.inner_ref().is_empty() {
+            return Err(Error::Profile(eyre!("no profile to check")));
+        }
+        self.inner_ref().iter().try_for_each(|p| check_profile(p))
+    }
+}
+
+fn check_profile(profile: &Profile) -> Result<()> {
+    let single = CompleteProfile::from_iter(iter::once(profile));
+    let ctx = RencCtx::create(&single)?;
+
+    let inst = RencBuilder::create(&single)
+        .build_instore()
+        .renced_stored(&ctx, profile.settings.cache_in_store.clone().into())
+        .inner();
+
+    inst.values().try_for_each(|p| {
+        debug!("checking in-store path: {}", p.path.display());
+        if !p.path.exists() {
+            error!("Forget adding it to git? Please run renc and add new production to git");
+            error!("See https://milieuim.github.io/vaultix/nix-apps.html#renc");
+            return Err(Error::NotRenced(p.path.clone()));
+        }
+        Ok(())
+    })?;
+
+    // extra recipients must be found in every cache file header
+    inst.iter()
+        .filter(|((_, h), _)| !h.extra_recips().is_empty())
+        .try_for_each(|((_, h), p)| {
+            let buf = p.read_buffer()?;
+            let stanzas = parse_stanzas(&buf)
+                .with_context(|| eyre!("malformed cache file: {}", p.path.display()))?;
+            if stanzas.len() < 1 + h.extra_recips().len() {
+                bail!(
+                    "cache {} has {} recipient(s), expect {}",
+                    p.path.display(),
+                    stanzas.len(),
+                    1 + h.extra_recips().len()
+                );
             }
-            Ok(())
-        })
+            // x25519 and plugin stanzas don't name their key, only counted
+            let mut x25519 = stanzas.iter().filter(|s| s.tag == "X25519").count();
+            let mut plugin = stanzas
+                .iter()
+                .filter(|s| !["X25519", "scrypt"].contains(&s.tag) && !s.tag.starts_with("ssh-"))
+                .count();
+            let mut take = |recip: &RawRecip| match recip.stanza_kind() {
+                Some(StanzaKind::Ssh(kind, tag)) => stanzas
+                    .iter()
+                    .any(|s| s.tag == kind && s.args.first() == Some(&tag.as_str())),
+                Some(StanzaKind::X25519) if x25519 > 0 => {
+                    x25519 -= 1;
+                    true
+                }
+                Some(StanzaKind::Plugin(_)) if plugin > 0 => {
+                    plugin -= 1;
+                    true
+                }
+                Some(_) => false,
+                None => true,
+            };
+            // host's own stanza is not what's checked here, but never counts for extras
+            take(&RawRecip::from(h.recip().trim().to_string()));
+            h.extra_recips().iter().try_for_each(|r| {
+                let recip = RawRecip::from(r.trim().to_string());
+                if !take(&recip) {
+                    bail!(Error::MissingRecipient {
+                        cache: p.path.clone(),
+                        recipient: recip.fingerprint(),
+                    })
+                }
+                Ok(())
+            })
+        })?;
+
+    let host_cache: PathBuf = profile.settings.cache_in_store.clone().into();
+    let Some(rotation) = RotationRecord::read(&host_cache)? else {
+        return Ok(());
+    };
+
+    let current = RawRecip::from(profile.host_pubkey().to_string()).fingerprint();
+    if current != rotation.new.fingerprint {
+        return Err(Error::Rotation(format!(
+            "hostPubkey of {} is {}, but last rotation moved it to {}",
+            profile.host_identifier(),
+            current,
+            rotation.new.fingerprint
+        )));
     }
+
+    // cache named after old key means it's still encrypted to it
+    ctx.inner_ref().iter().try_for_each(|i| {
+        let mut leftover = host_cache.clone();
+        leftover.push(
+            i.value()
+                .hash_with(&rotation.old.pubkey, &profile.settings.extra_recipients)
+                .to_string(),
+        );
+        if leftover.exists() {
+            return Err(Error::Rotation(format!(
+                "cache {} still encrypted to rotated key {}, run renc and remove it",
+                leftover.display(),
+                rotation.old.fingerprint
+            )));
+        }
+        Ok(())
+    })
 }
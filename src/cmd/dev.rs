@@ -0,0 +1,246 @@
+use std::{
+    ffi::{CString, OsStr},
+    fs::{self, DirBuilder, OpenOptions},
+    io::{self, Write},
+    iter,
+    mem::MaybeUninit,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::{DirBuilderExt, OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use age::secrecy::{ExposeSecret, SecretSlice};
+use eyre::{Context, Result, bail, eyre};
+use log::info;
+
+use crate::{
+    parser::identity::{ParsedIdentity, RawIdentity},
+    profile::dev::DevProfile,
+    util::{
+        secbuf::{AgeEnc, Decryptable, SecBuf},
+        secmap::{GetSec, InRepo, SecPathBuf},
+    },
+};
+
+use super::{EnvSubCmd, ShellSubCmd, undeploy::wipe};
+
+/// exported to the shell, tells which dev profile is loaded
+const DEV_ENV: &str = "VAULTIX_DEV";
+
+/// not exported by libc
+const RAMFS_MAGIC: i64 = 0x858458f6;
+
+fn memory_backed(path: &Path) -> Result<bool> {
+    let c = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(c.as_ptr(), buf.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error())
+            .wrap_err_with(|| eyre!("statfs {}", path.display()));
+    }
+    let f_type = unsafe { buf.assume_init() }.f_type as i64;
+    Ok(f_type == libc::TMPFS_MAGIC as i64 || f_type == RAMFS_MAGIC)
+}
+
+/**
+Private dir for path style secrets, under `$XDG_RUNTIME_DIR` or `/dev/shm`
+
+Refused unless on tmpfs or ramfs, so plaintext never reaches a disk.
+*/
+fn runtime_dir(profile: &DevProfile, suffix: Option<u32>) -> Result<PathBuf> {
+    let base = std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| "/dev/shm".into());
+    if profile.secrets.values().any(|s| s.path) && !memory_backed(&base)? {
+        bail!(
+            "{} is not memory backed, refuse to place secrets",
+            base.display()
+        );
+    }
+    let key = blake3::hash(profile.source.as_os_str().as_bytes()).to_hex();
+    let mut name = key[..16].to_string();
+    if let Some(s) = suffix {
+        name.push_str(&format!("-{}", s));
+    }
+    Ok(base.join("vaultix-dev").join(name))
+}
+
+fn clean(dir: &Path) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for e in fs::read_dir(dir)? {
+        let path = e?.path();
+        wipe(&path).wrap_err_with(|| eyre!("wipe {}", path.display()))?;
+    }
+    fs::remove_dir(dir)?;
+    Ok(())
+}
+
+/// variable name and value, path of the written file for path style ones
+fn load(
+    profile: &DevProfile,
+    identity: Vec<String>,
+    dir: &Path,
+) -> Result<Vec<(String, SecretSlice<u8>)>> {
+    let errs = profile.check();
+    if !errs.is_empty() {
+        bail!("invalid dev profile: {}", errs.join(", "));
+    }
+    let ParsedIdentity { identity, .. } = RawIdentity::from(identity).try_into()?;
+
+    let mut vars = Vec::new();
+    for (id, s) in &profile.secrets {
+        let plain: SecretSlice<u8> = SecPathBuf::<InRepo>::new(s.file.clone())
+            .read_buffer()
+            .map(SecBuf::<AgeEnc>::from)?
+            .decrypt(identity.as_ref())
+            .wrap_err_with(|| eyre!("decrypt {} error", id))?
+            .inner()
+            .into();
+        let value = if s.path {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+            let path = dir.join(id);
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+                .and_then(|mut f| f.write_all(plain.expose_secret()))
+                .wrap_err_with(|| eyre!("write {} error", path.display()))?;
+            path.into_os_string().into_vec().into()
+        } else {
+            if plain.expose_secret().contains(&0) {
+                bail!("{} contains nul byte, set `path` for it", id);
+            }
+            plain
+        };
+        vars.push((s.env.clone(), value));
+    }
+    Ok(vars)
+}
+
+/// single quoted for posix shells
+fn quote(value: &[u8]) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for b in value {
+        match b {
+            b'\'' => quoted.extend_from_slice(b"'\\''"),
+            b => quoted.push(*b),
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/**
+Spawn a shell, or the given command, with secrets of dev profile set
+
+Files of path style secrets are wiped once it exits.
+*/
+pub fn shell(arg: &ShellSubCmd) -> Result<()> {
+    let profile = DevProfile::from_file(&arg.profile)?;
+    let dir = runtime_dir(&profile, Some(std::process::id()))?;
+    let vars = match load(&profile, arg.identity.clone(), &dir) {
+        Ok(v) => v,
+        Err(e) => {
+            clean(&dir)?;
+            return Err(e);
+        }
+    };
+
+    let mut cmd = match arg.command.split_first() {
+        Some((program, args)) => {
+            let mut c = Command::new(program);
+            c.args(args);
+            c
+        }
+        None => Command::new(std::env::var_os("SHELL").unwrap_or_else(|| "/bin/sh".into())),
+    };
+    cmd.env(DEV_ENV, &profile.source).envs(
+        vars.iter()
+            .map(|(k, v)| (k, OsStr::from_bytes(v.expose_secret()))),
+    );
+    info!(
+        "{} secret(s) loaded from {}",
+        vars.len(),
+        profile.source.display()
+    );
+    let status = cmd.spawn().map(|mut child| {
+        // interrupts belong to the shell, keep waiting to clean up
+        let prev =
+            [libc::SIGINT, libc::SIGQUIT].map(|s| (s, unsafe { libc::signal(s, libc::SIG_IGN) }));
+        let status = child.wait();
+        for (s, h) in prev {
+            unsafe { libc::signal(s, h) };
+        }
+        status
+    });
+    drop(vars);
+    clean(&dir).wrap_err_with(|| eyre!("clean {} error", dir.display()))?;
+
+    let status = status
+        .and_then(|s| s)
+        .wrap_err_with(|| eyre!("run {:?} error", cmd.get_program()))?;
+    if !status.success() {
+        bail!("{:?} exited with {}", cmd.get_program(), status);
+    }
+    Ok(())
+}
+
+/**
+Print `export` lines of secrets in dev profile, for `eval`
+
+Files of path style secrets stay until `--unset`, which prints `unset`
+lines and wipes them.
+*/
+pub fn env(arg: &EnvSubCmd) -> Result<()> {
+    let profile = DevProfile::from_file(&arg.profile)?;
+    let dir = runtime_dir(&profile, None)?;
+    let mut out = io::stdout().lock();
+    // loaded again, stale files replaced
+    clean(&dir)?;
+    if arg.unset {
+        for s in profile.secrets.values() {
+            writeln!(out, "unset {}", s.env)?;
+        }
+        writeln!(out, "unset {}", DEV_ENV)?;
+        return Ok(());
+    }
+
+    let vars = match load(&profile, arg.identity.clone(), &dir) {
+        Ok(v) => v,
+        Err(e) => {
+            clean(&dir)?;
+            return Err(e);
+        }
+    };
+    let res = iter::once((DEV_ENV, profile.source.as_os_str().as_bytes()))
+        .chain(vars.iter().map(|(k, v)| (k.as_str(), v.expose_secret())))
+        .try_for_each(|(k, v)| {
+            let quoted: SecretSlice<u8> = quote(v).into();
+            write!(out, "export {}=", k)?;
+            out.write_all(quoted.expose_secret())?;
+            writeln!(out)
+        })
+        .and_then(|_| out.flush());
+    if let Err(e) = res {
+        clean(&dir)?;
+        return Err(e).wrap_err("write exports error");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shell_quote() {
+        assert_eq!(quote(b"plain"), b"'plain'");
+        assert_eq!(quote(b"it's $HOME"), b"'it'\\''s $HOME'");
+        assert_eq!(quote(b""), b"''");
+    }
+}
@@ -1,7 +1,6 @@
 use std::{
     fs::{self, OpenOptions},
     io::Write,
-    iter,
     path::PathBuf,
 };
 
@@ -19,7 +18,7 @@ use crate::{
 
 use crate::util::secbuf::Decryptable;
 use age::Recipient;
-use eyre::{Context, ContextCompat, eyre};
+use eyre::{Context, eyre};
 use log::info;
 use nom::AsBytes;
 
@@ -30,18 +29,32 @@ pub fn edit(arg: EditSubCmd) -> eyre::Result<()> {
         file,
         identity,
         recipient,
+        recipients_file,
     } = arg;
 
-    let id_parsed: ParsedIdentity = identity
-        .with_context(|| eyre!("must provide identity to decrypt content"))
-        .and_then(|i| RawIdentity::from(i).try_into())?;
+    let ParsedIdentity {
+        identity,
+        recipients: id_recips,
+    } = RawIdentity::from(identity).try_into()?;
+
     let recips: Vec<Box<dyn Recipient + Send>> = recipient
         .into_iter()
-        .map(|s| {
-            TryInto::<Box<dyn Recipient + Send>>::try_into(RawRecip::from(s)).expect("convert")
-        })
-        .chain::<std::iter::Once<Box<dyn Recipient + Send>>>(iter::once(id_parsed.recipient))
-        .collect();
+        .map(RawRecip::from)
+        .chain(
+            recipients_file
+                .iter()
+                .map(RawRecip::from_file)
+                .try_collect::<Vec<_>>()?
+                .into_iter()
+                .flatten(),
+        )
+        .map(TryInto::<Box<dyn Recipient + Send>>::try_into)
+        .chain(id_recips.into_iter().map(Ok))
+        .try_collect()?;
+    if recips.is_empty() {
+        // decrypting through agent gives no recipient of identity
+        return Err(eyre!("no recipient to encrypt to, provide with -r or -R"));
+    }
     let decrypt = |v: Vec<u8>| -> eyre::Result<Vec<u8>> {
         Ok(SecBuf::<Plain>::new(v)
             .encrypt(recips.iter().map(|i| i.as_ref()))?
@@ -52,7 +65,7 @@ pub fn edit(arg: EditSubCmd) -> eyre::Result<()> {
         let buf = SecPath::<String, InRepo>::new(file.clone())
             .read_buffer()
             .map(SecBuf::<AgeEnc>::from)?
-            .decrypt(id_parsed.identity.as_ref())?
+            .decrypt(identity.as_ref())?
             .inner();
         let pre_hash = blake3::hash(buf.as_slice());
 
@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsStr},
+    fs::File,
+    io::{self, Seek, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    ptr,
+};
+
+use age::secrecy::{ExposeSecret, SecretSlice};
+use eyre::{Context, ContextCompat, Result, bail, eyre};
+use log::{debug, info};
+
+use crate::cmd::renc::CompleteProfile;
+
+use super::ExecSubCmd;
+
+/// where a secret is handed to the child
+#[derive(Debug, PartialEq, Eq)]
+enum Sink {
+    Env(String),
+    Memfd(i32),
+    Pipe(i32),
+}
+
+/// `NAME=id` or `3=id`
+fn split(s: &str) -> Result<(&str, &str)> {
+    match s.split_once('=') {
+        Some((k, id)) if !k.is_empty() && !id.is_empty() => Ok((k, id)),
+        _ => bail!("`{}` not in form of `<target>=<secret id>`", s),
+    }
+}
+
+fn sinks(arg: &ExecSubCmd) -> Result<Vec<(Sink, String)>> {
+    let fd = |s: &str| -> Result<(i32, String)> {
+        let (fd, id) = split(s)?;
+        match fd.parse::<i32>() {
+            Ok(n) if n > 2 => Ok((n, id.to_string())),
+            _ => bail!("`{}` is not a descriptor above stderr", fd),
+        }
+    };
+    let mut sinks = Vec::new();
+    for e in &arg.env {
+        let (name, id) = split(e)?;
+        sinks.push((Sink::Env(name.to_string()), id.to_string()));
+    }
+    for s in &arg.fd {
+        let (n, id) = fd(s)?;
+        sinks.push((Sink::Memfd(n), id));
+    }
+    for s in &arg.pipe {
+        let (n, id) = fd(s)?;
+        sinks.push((Sink::Pipe(n), id));
+    }
+    let mut targets: Vec<i32> = sinks
+        .iter()
+        .filter_map(|(s, _)| match s {
+            Sink::Memfd(n) | Sink::Pipe(n) => Some(*n),
+            Sink::Env(_) => None,
+        })
+        .collect();
+    targets.sort_unstable();
+    if let Some(w) = targets.windows(2).find(|w| w[0] == w[1]) {
+        bail!("descriptor {} given twice", w[0]);
+    }
+    Ok(sinks)
+}
+
+fn cvt(res: libc::c_int) -> io::Result<libc::c_int> {
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+/// moved above every target, so placing one never clobbers another
+fn above(fd: OwnedFd, min: i32) -> io::Result<OwnedFd> {
+    let raised = cvt(unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_DUPFD_CLOEXEC, min) })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(raised) })
+}
+
+/// sealed against any change, positioned at start
+fn memfd(id: &str, content: &[u8]) -> Result<OwnedFd> {
+    let name = CString::new(format!("vaultix:{}", id))?;
+    let fd = cvt(unsafe {
+        libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING)
+    })
+    .wrap_err("memfd_create failed")?;
+    let mut f = unsafe { File::from_raw_fd(fd) };
+    f.write_all(content)?;
+    cvt(unsafe {
+        libc::fcntl(
+            f.as_raw_fd(),
+            libc::F_ADD_SEALS,
+            libc::F_SEAL_WRITE | libc::F_SEAL_GROW | libc::F_SEAL_SHRINK | libc::F_SEAL_SEAL,
+        )
+    })
+    .wrap_err("seal memfd failed")?;
+    f.rewind()?;
+    Ok(f.into())
+}
+
+/// filled and closed for writing before exec, read end returned
+fn pipe(content: &[u8]) -> Result<OwnedFd> {
+    let mut fds = [0; 2];
+    cvt(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }).wrap_err("pipe failed")?;
+    let (read, mut write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    // nobody reads until exec, all of it must fit in pipe buffer
+    if content.len() > 1 << 16 {
+        cvt(unsafe {
+            libc::fcntl(
+                write.as_raw_fd(),
+                libc::F_SETPIPE_SZ,
+                content.len() as libc::c_int,
+            )
+        })
+        .wrap_err("secret exceeds pipe capacity, use `--fd` instead")?;
+    }
+    write.write_all(content)?;
+    Ok(read)
+}
+
+/// `NAME=value\0` wiped on drop, if exec fails
+fn env_entry(name: &OsStr, value: &[u8]) -> Result<SecretSlice<u8>> {
+    if name.as_bytes().contains(&b'=') || [name.as_bytes(), value].concat().contains(&0) {
+        bail!("environment {:?} contains `=` in name or nul byte", name);
+    }
+    let mut entry = Vec::with_capacity(name.len() + value.len() + 2);
+    entry.extend_from_slice(name.as_bytes());
+    entry.push(b'=');
+    entry.extend_from_slice(value);
+    entry.push(0);
+    Ok(entry.into())
+}
+
+/**
+Decrypt the wanted secrets and replace this process with the command
+
+Secrets go to environment, sealed memfds or pipes of the child, never to
+disk. Secrets not asked for are never decrypted, plaintext copies are
+wiped before exec, the rest lives only in the new process image. Returns only on failure.
+*/
+pub fn exec(profiles: &CompleteProfile, arg: &ExecSubCmd) -> Result<()> {
+    let Some(program) = arg.command.first() else {
+        bail!("no command given, append it after `--`");
+    };
+    let sinks = sinks(arg)?;
+    profiles.ensure_mergeable()?;
+
+    let mut plain: HashMap<String, SecretSlice<u8>> = HashMap::new();
+    for p in profiles.inner_ref() {
+        // others are never decrypted
+        let mut p = (*p).clone();
+        p.secrets.retain(|id, _| sinks.iter().any(|(_, i)| i == id));
+        if p.secrets.is_empty() {
+            continue;
+        }
+        let mut decrypted: HashMap<_, SecretSlice<u8>> = p
+            .decrypt()?
+            .into_iter()
+            .map(|(id, v)| (id, v.into()))
+            .collect();
+        for (id, s) in &p.secrets {
+            let content = decrypted
+                .remove(&s.id)
+                .wrap_err_with(|| eyre!("decrypted content of {} not found", id))?;
+            plain.insert(id.clone(), content);
+        }
+    }
+    let missing: Vec<&str> = sinks
+        .iter()
+        .map(|(_, id)| id.as_str())
+        .filter(|id| !plain.contains_key(*id))
+        .collect();
+    if !missing.is_empty() {
+        bail!("secret not found in profiles: {}", missing.join(", "));
+    }
+
+    let min = sinks
+        .iter()
+        .filter_map(|(s, _)| match s {
+            Sink::Memfd(n) | Sink::Pipe(n) => Some(*n + 1),
+            Sink::Env(_) => None,
+        })
+        .max()
+        .unwrap_or(3);
+    let mut injected = Vec::new();
+    let mut moves = Vec::new();
+    for (sink, id) in &sinks {
+        let content = plain[id].expose_secret();
+        match sink {
+            Sink::Env(name) => injected.push(env_entry(OsStr::new(name), content)?),
+            Sink::Memfd(n) => moves.push((above(memfd(id, content)?, min)?, *n)),
+            Sink::Pipe(n) => moves.push((above(pipe(content)?, min)?, *n)),
+        }
+        debug!("secret {} -> {:?}", id, sink);
+    }
+    drop(plain);
+
+    let names: Vec<&str> = sinks
+        .iter()
+        .filter_map(|(s, _)| match s {
+            Sink::Env(n) => Some(n.as_str()),
+            _ => None,
+        })
+        .collect();
+    let mut env: Vec<SecretSlice<u8>> = std::env::vars_os()
+        .filter(|(k, _)| !names.iter().any(|n| OsStr::new(n) == k))
+        .filter_map(|(k, v)| env_entry(&k, v.as_bytes()).ok())
+        .collect();
+    env.extend(injected);
+    let envp: Vec<*const libc::c_char> = env
+        .iter()
+        .map(|e| e.expose_secret().as_ptr().cast())
+        .chain([ptr::null()])
+        .collect();
+    let args = arg
+        .command
+        .iter()
+        .map(|a| CString::new(a.as_str()))
+        .collect::<Result<Vec<_>, _>>()
+        .wrap_err("nul byte in command")?;
+    let argv: Vec<*const libc::c_char> = args
+        .iter()
+        .map(|a| a.as_ptr())
+        .chain([ptr::null()])
+        .collect();
+
+    // sources stay close-on-exec, their copies on targets don't
+    for (src, dst) in &moves {
+        cvt(unsafe { libc::dup2(src.as_raw_fd(), *dst) })
+            .wrap_err_with(|| eyre!("place secret on descriptor {}", dst))?;
+    }
+    info!("exec {} with {} secret(s)", program, sinks.len());
+    unsafe { libc::execvpe(argv[0], argv.as_ptr(), envp.as_ptr()) };
+    Err(io::Error::last_os_error()).wrap_err_with(|| eyre!("exec {} error", program))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn parse_sinks() {
+        let arg = |env: &[&str], fd: &[&str], pipe: &[&str]| ExecSubCmd {
+            env: env.iter().map(|s| s.to_string()).collect(),
+            fd: fd.iter().map(|s| s.to_string()).collect(),
+            pipe: pipe.iter().map(|s| s.to_string()).collect(),
+            cache: None,
+            command: vec!["true".into()],
+        };
+        assert_eq!(
+            sinks(&arg(&["TOKEN=api"], &["3=db"], &["4=tls"])).unwrap(),
+            [
+                (Sink::Env("TOKEN".into()), "api".into()),
+                (Sink::Memfd(3), "db".into()),
+                (Sink::Pipe(4), "tls".into())
+            ]
+        );
+        for bad in [
+            arg(&["TOKEN"], &[], &[]),
+            arg(&[], &["2=db"], &[]),
+            arg(&[], &["3=db"], &["3=tls"]),
+            arg(&[], &["x=db"], &[]),
+        ] {
+            assert!(sinks(&bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn sealed_memfd() {
+        let fd = memfd("t", b"content").unwrap();
+        let mut f = File::from(fd);
+        let mut buf = String::new();
+        f.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "content");
+        f.rewind().unwrap();
+        assert!(f.write_all(b"x").is_err());
+    }
+}
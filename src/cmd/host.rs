@@ -0,0 +1,211 @@
+use std::{fs, iter, path::PathBuf};
+
+use age::Recipient;
+use eyre::{Context, ContextCompat, Result, bail, eyre};
+use log::{error, info, warn};
+
+use crate::{
+    parser::{
+        identity::{ParsedIdentity, RawIdentity},
+        known_hosts::parse_known_hosts,
+        recipient::RawRecip,
+    },
+    profile::Profile,
+    util::{
+        registry::HostRegistry,
+        rotation::{KeyRecord, RotationRecord},
+    },
+};
+
+use super::{HostImportSubCmd, HostRotateSubCmd, HostVerifySubCmd, renc::CompleteProfile};
+
+// only ed25519 host key could decrypt while deploying
+const KEY_TYPE: &str = "ssh-ed25519";
+
+/// key string, or path of a file containing it
+fn read_pubkey(key_or_file: &str) -> Result<RawRecip> {
+    let p = PathBuf::from(key_or_file);
+    let s = if p.is_file() {
+        fs::read_to_string(&p).wrap_err_with(|| eyre!("read pubkey file error: {}", p.display()))?
+    } else {
+        key_or_file.to_string()
+    };
+    // cache name is hashed with exact `hostPubkey` string, which never
+    // carries the comment and newline a `.pub` file ends with
+    let s = match s.split_whitespace().collect::<Vec<_>>()[..] {
+        [key_type, key, ..] => format!("{} {}", key_type, key),
+        _ => bail!("invalid host pubkey: {}", key_or_file),
+    };
+    TryInto::<Box<dyn Recipient + Send>>::try_into(RawRecip::from(s.clone()))
+        .wrap_err_with(|| eyre!("invalid host pubkey: {}", key_or_file))?;
+    Ok(RawRecip::from(s))
+}
+
+/**
+Re-encrypt one host's caches to its new public key
+
+Old key is taken from the profile unless `--from` given, which is needed
+when `hostPubkey` was already updated. Outdated caches of this host are
+removed by renc, the fingerprints are recorded in the host cache dir so
+`check` could find leftovers.
+*/
+pub fn rotate(profiles: &[Profile], flake_root: PathBuf, arg: &HostRotateSubCmd) -> Result<()> {
+    let HostRotateSubCmd {
+        id,
+        pubkey,
+        from,
+        identity,
+        cache,
+    } = arg;
+
+    let mut profile: Profile = profiles
+        .iter()
+        .find(|p| p.host_identifier() == id)
+        .cloned()
+        .with_context(|| eyre!("host `{}` not found in given profiles", id))?;
+
+    let new = read_pubkey(pubkey)?;
+    let old = match from {
+        Some(f) => read_pubkey(f)?,
+        None => RawRecip::from(profile.host_pubkey().to_string()),
+    };
+
+    if old.fingerprint() == new.fingerprint() {
+        bail!(
+            "old and new key of `{}` are the same ({}), pass previous key with `--from`",
+            id,
+            old.fingerprint()
+        );
+    }
+    info!(
+        "rotating host key of {}: {} -> {}",
+        id,
+        old.fingerprint(),
+        new.fingerprint()
+    );
+
+    profile.settings.host_pubkey = new.as_ref().to_string();
+
+    let ParsedIdentity { identity, .. } = RawIdentity::from(identity.clone()).try_into()?;
+    let cache_path: PathBuf = cache.into();
+    let report = CompleteProfile::from_iter(iter::once(&profile)).renc(
+        flake_root,
+        identity.as_ref(),
+        cache_path.clone(),
+        vec![id.clone()],
+    )?;
+    if !report.failed.is_empty() {
+        bail!(
+            "{} cache(s) of {} failed to write, rotation not recorded",
+            report.failed.len(),
+            id
+        );
+    }
+
+    RotationRecord {
+        host: id.clone(),
+        old: KeyRecord::from(&old),
+        new: KeyRecord::from(&new),
+    }
+    .write(cache_path.join(id))?;
+
+    if profiles
+        .iter()
+        .filter(|p| p.host_identifier() == id)
+        .any(|p| RawRecip::from(p.host_pubkey().to_string()).fingerprint() != new.fingerprint())
+    {
+        warn!(
+            "remember to update `hostPubkey` of {} to the new key, otherwise check will fail",
+            id
+        );
+    }
+    info!("rotation of {} recorded", id);
+    Ok(())
+}
+
+/**
+Import ed25519 host keys into registry
+
+With profiles given, entries are registered under matching host
+identifiers, otherwise under the first hostname of each entry.
+*/
+pub fn import(profiles: &[Profile], arg: &HostImportSubCmd) -> Result<()> {
+    let HostImportSubCmd { files, registry } = arg;
+    let mut reg = HostRegistry::load(registry)?;
+
+    for f in files {
+        let content =
+            fs::read_to_string(f).wrap_err_with(|| eyre!("read known hosts error: {}", f))?;
+        for entry in parse_known_hosts(content.as_str())
+            .iter()
+            .filter(|e| e.key_type == KEY_TYPE)
+        {
+            let ids: Vec<&str> = if profiles.is_empty() {
+                entry.hosts.first().copied().into_iter().collect()
+            } else {
+                profiles
+                    .iter()
+                    .map(|p| p.host_identifier())
+                    .filter(|id| entry.matches(id))
+                    .collect()
+            };
+            for id in ids {
+                match reg.insert(id, entry.pubkey()) {
+                    Err(e) => {
+                        warn!("{:#}, skipped entry of {} in {}", e, id, f);
+                        continue;
+                    }
+                    Ok(Some(old)) => warn!(
+                        "registered key of {} changed: {} -> {}",
+                        id,
+                        RawRecip::from(old).fingerprint(),
+                        RawRecip::from(entry.pubkey()).fingerprint()
+                    ),
+                    Ok(None) => {}
+                }
+                info!("imported {} from {}", id, f);
+            }
+        }
+    }
+    reg.save(registry)?;
+
+    reg.mismatches(profiles)
+        .iter()
+        .for_each(|m| warn!("hostPubkey of {} differs from registry", m.id));
+    Ok(())
+}
+
+pub fn verify(profiles: &[Profile], arg: &HostVerifySubCmd) -> Result<()> {
+    let reg = HostRegistry::load(&arg.registry)?;
+    let mismatches = reg.mismatches(profiles);
+
+    mismatches.iter().for_each(|m| match &m.registry {
+        Some(r) => error!("{}: hostPubkey {} but registry has {}", m.id, m.profile, r),
+        None => error!("{}: not found in registry", m.id),
+    });
+    if !mismatches.is_empty() {
+        bail!("{} host(s) mismatch with registry", mismatches.len());
+    }
+    info!("all {} host(s) match registry", profiles.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pubkey_from_file() {
+        let key =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIByDbdWF3pWaCRMNtcc1mo1bmkwdClayKneKv2JfJQ3v";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("host.pub");
+        for content in [format!("{}\n", key), format!("{}  root@web\n", key)] {
+            fs::write(&path, content).unwrap();
+            let from_file = read_pubkey(path.to_str().unwrap()).unwrap();
+            assert_eq!(from_file.as_ref(), key);
+        }
+        assert_eq!(read_pubkey(key).unwrap().as_ref(), key);
+        assert!(read_pubkey("ssh-ed25519").is_err());
+    }
+}
@@ -0,0 +1,56 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+};
+
+use eyre::{Context, ContextCompat, Result, eyre};
+use log::info;
+
+use crate::{parser::identity::protect_identity, util::callback::request_new_passphrase};
+
+use super::IdentityProtectSubCmd;
+
+/**
+Encrypt identity file with passphrase, in place unless output given
+
+Written to a temporary file beside the target then renamed, so the plain
+file is never left half overwritten.
+*/
+pub fn protect(arg: &IdentityProtectSubCmd) -> Result<()> {
+    let IdentityProtectSubCmd { file, output } = arg;
+
+    let content = fs::read(file).wrap_err_with(|| eyre!("read identity error: {}", file))?;
+    let passphrase = request_new_passphrase(&format!("New passphrase of identity {}", file))
+        .with_context(|| eyre!("passphrase required to protect {}", file))?;
+    let protected = protect_identity(&content, passphrase)?;
+
+    let target = PathBuf::from(output.as_ref().unwrap_or(file));
+    let mut tmp = target.clone();
+    tmp.set_extension("vaultix-tmp");
+    // left by an interrupted run, never anything but our own output
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            return Err(e).wrap_err_with(|| eyre!("remove stale temp file: {}", tmp.display()));
+        }
+        _ => (),
+    }
+
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .wrap_err_with(|| eyre!("create temp file error: {}", tmp.display()))?;
+    f.write_all(&protected)
+        .and_then(|_| f.sync_all())
+        .and_then(|_| fs::rename(&tmp, &target))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&tmp);
+        })
+        .wrap_err_with(|| eyre!("write protected identity error: {}", target.display()))?;
+
+    info!("identity protected: {}", target.display());
+    Ok(())
+}
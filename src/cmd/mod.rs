@@ -1,14 +1,30 @@
-use std::{fs, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
-use eyre::{eyre, Context, ContextCompat};
 use log::info;
 use renc::CompleteProfile;
+
+use crate::{
+    parser::identity::{ParsedIdentity, RawIdentity},
+    util::callback::{Interaction, PassphraseSource, set_interaction},
+};
 use {argh::FromArgs, std::fmt::Debug};
 
+mod agent;
 mod check;
-mod deploy;
+pub mod deploy;
+mod dev;
 mod edit;
+mod exec;
+mod host;
+mod identity;
+mod profile;
 pub mod renc;
+mod serve;
+pub mod undeploy;
+pub mod verify;
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Vaultix cli | Secret manager for NixOS
@@ -19,8 +35,20 @@ pub struct Args {
     /// secret profile
     profile: Vec<String>,
     #[argh(option, short = 'f')]
-    /// toplevel of flake repository
+    /// toplevel of flake repository, or directory containing `.vaultix`
     flake_root: Option<String>,
+    #[argh(switch)]
+    /// fail instead of prompting, for CI
+    no_interactive: bool,
+    #[argh(option)]
+    /// read identity passphrase from this file descriptor
+    passphrase_fd: Option<i32>,
+    #[argh(option)]
+    /// read identity passphrase from environment variable of this name
+    passphrase_env: Option<String>,
+    #[argh(option)]
+    /// read identity passphrase from file
+    passphrase_file: Option<String>,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -30,6 +58,17 @@ enum SubCmd {
     Edit(EditSubCmd),
     Check(CheckSubCmd),
     Deploy(DeploySubCmd),
+    Undeploy(UndeploySubCmd),
+    Verify(VerifySubCmd),
+    Serve(ServeSubCmd),
+    Fetch(FetchSubCmd),
+    Exec(ExecSubCmd),
+    Shell(ShellSubCmd),
+    Env(EnvSubCmd),
+    Host(HostSubCmd),
+    Agent(AgentSubCmd),
+    Identity(IdentitySubCmd),
+    Profile(ProfileSubCmd),
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -37,11 +76,14 @@ enum SubCmd {
 #[argh(subcommand, name = "renc")]
 pub struct RencSubCmd {
     #[argh(option, short = 'i')]
-    /// identity for decrypt secret
-    identity: String,
+    /// identity for decrypt secret, repeatable and tried in turn
+    identity: Vec<String>,
     #[argh(option, short = 'c')]
     /// identity for decrypt secret
     cache: String,
+    #[argh(option)]
+    /// only re-encrypt for matching host identifier, glob supported
+    host: Vec<String>,
 }
 
 #[derive(FromArgs, PartialEq, Debug, Clone)]
@@ -52,11 +94,14 @@ pub struct EditSubCmd {
     /// file to edit
     file: String,
     #[argh(option, short = 'i')]
-    /// identity for decrypt secret
-    identity: Option<String>,
+    /// identity for decrypt secret, repeatable and tried in turn
+    identity: Vec<String>,
     #[argh(option, short = 'r')]
     /// recipients for encrypt secrets
     recipient: Vec<String>,
+    #[argh(option, short = 'R')]
+    /// file of recipients for encrypt secrets, one per line
+    recipients_file: Vec<String>,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -64,8 +109,260 @@ pub struct EditSubCmd {
 #[argh(subcommand, name = "deploy")]
 pub struct DeploySubCmd {
     #[argh(switch, short = 'e')]
-    /// deploy before users init
+    /// deploy before users init, same as `--phase early`
     early: bool,
+    #[argh(option)]
+    /// phase to deploy, default `default`
+    phase: Option<String>,
+    #[argh(option, short = 'c')]
+    /// cache dir containing per host caches, instead of `cacheInStore`
+    cache: Option<String>,
+    #[argh(option)]
+    /// staging root to deploy under, for image builds and tests
+    root: Option<String>,
+    #[argh(switch)]
+    /// don't mount secrets filesystem under `--root`, secrets land on disk
+    no_mount: bool,
+    #[argh(option)]
+    /// map user to uid instead of looking up this system, `name=uid`, repeatable
+    map_user: Vec<String>,
+    #[argh(option)]
+    /// map group to gid instead of looking up this system, `name=gid`, repeatable
+    map_group: Vec<String>,
+}
+
+/// `name=id` pairs of `--map-user` and `--map-group`
+fn id_map(pairs: &[String]) -> eyre::Result<HashMap<String, u32>> {
+    pairs
+        .iter()
+        .map(|p| {
+            let (name, id) = p
+                .split_once('=')
+                .ok_or_else(|| eyre::eyre!("`{}` not in form of `name=id`", p))?;
+            let id = id
+                .parse()
+                .map_err(|_| eyre::eyre!("invalid id `{}` of `{}`", id, name))?;
+            Ok((name.to_string(), id))
+        })
+        .collect()
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Wipe deployed secrets, remove links and unmount secrets filesystem
+#[argh(subcommand, name = "undeploy")]
+pub struct UndeploySubCmd {
+    #[argh(option)]
+    /// staging root deployed under with `deploy --root`
+    root: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Compare deployed secrets with current generation
+#[argh(subcommand, name = "verify")]
+pub struct VerifySubCmd {
+    #[argh(switch)]
+    /// fix drifts in place, without a new generation
+    repair: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Hold secrets with `serve` policy in memory and hand them out on a unix socket
+#[argh(subcommand, name = "serve")]
+pub struct ServeSubCmd {
+    #[argh(option)]
+    /// socket path, default $VAULTIX_SERVE_SOCK or /run/vaultix-serve.sock
+    socket: Option<String>,
+    #[argh(option)]
+    /// append audit records to this file instead of the log
+    audit_log: Option<String>,
+    #[argh(option, short = 'c')]
+    /// cache dir containing per host caches, instead of `cacheInStore`
+    cache: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print a secret handed out by `vaultix serve`
+#[argh(subcommand, name = "fetch")]
+pub struct FetchSubCmd {
+    #[argh(positional)]
+    /// secret id
+    id: String,
+    #[argh(option)]
+    /// socket path, default $VAULTIX_SERVE_SOCK or /run/vaultix-serve.sock
+    socket: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Run a command with secrets in its environment or descriptors, never on disk
+#[argh(subcommand, name = "exec")]
+pub struct ExecSubCmd {
+    #[argh(option)]
+    /// environment variable from secret, `NAME=<secret id>`, repeatable
+    env: Vec<String>,
+    #[argh(option)]
+    /// sealed memfd on descriptor from secret, `3=<secret id>`, repeatable
+    fd: Vec<String>,
+    #[argh(option)]
+    /// prefilled pipe on descriptor from secret, `3=<secret id>`, repeatable
+    pipe: Vec<String>,
+    #[argh(option, short = 'c')]
+    /// cache dir containing per host caches, instead of `cacheInStore`
+    cache: Option<String>,
+    #[argh(positional, greedy)]
+    /// command and its arguments, after `--`
+    command: Vec<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Spawn a shell with secrets of a developer profile, decrypted by master identity
+#[argh(subcommand, name = "shell")]
+pub struct ShellSubCmd {
+    #[argh(positional)]
+    /// developer profile listing secrets to load
+    profile: String,
+    #[argh(option, short = 'i')]
+    /// identity for decrypt secret, repeatable and tried in turn
+    identity: Vec<String>,
+    #[argh(positional, greedy)]
+    /// command to run instead of $SHELL, after `--`
+    command: Vec<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Print export lines of secrets in a developer profile, for `eval`
+#[argh(subcommand, name = "env")]
+pub struct EnvSubCmd {
+    #[argh(positional)]
+    /// developer profile listing secrets to load
+    profile: String,
+    #[argh(option, short = 'i')]
+    /// identity for decrypt secret, repeatable and tried in turn
+    identity: Vec<String>,
+    #[argh(switch)]
+    /// print unset lines instead, and wipe files of path style secrets
+    unset: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Manage host keys
+#[argh(subcommand, name = "host")]
+pub struct HostSubCmd {
+    #[argh(subcommand)]
+    op: HostOp,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum HostOp {
+    Rotate(HostRotateSubCmd),
+    Import(HostImportSubCmd),
+    Verify(HostVerifySubCmd),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Re-encrypt caches of one host to its new public key
+#[argh(subcommand, name = "rotate")]
+pub struct HostRotateSubCmd {
+    #[argh(positional)]
+    /// host identifier
+    id: String,
+    #[argh(option)]
+    /// new host public key, or file contains it
+    pubkey: String,
+    #[argh(option)]
+    /// previous host public key, or file contains it. default from profile
+    from: Option<String>,
+    #[argh(option, short = 'i')]
+    /// identity for decrypt secret, repeatable and tried in turn
+    identity: Vec<String>,
+    #[argh(option, short = 'c')]
+    /// cache dir of re-encrypted secrets
+    cache: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Import host public keys from known_hosts or ssh-keyscan output
+#[argh(subcommand, name = "import")]
+pub struct HostImportSubCmd {
+    #[argh(positional)]
+    /// known_hosts or saved ssh-keyscan output
+    files: Vec<String>,
+    #[argh(option, default = "String::from(\"./secrets/hosts.json\")")]
+    /// host registry file
+    registry: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Compare hostPubkey of profiles with host registry
+#[argh(subcommand, name = "verify")]
+pub struct HostVerifySubCmd {
+    #[argh(option, default = "String::from(\"./secrets/hosts.json\")")]
+    /// host registry file
+    registry: String,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Hold unlocked identities and decrypt for local cli
+#[argh(subcommand, name = "agent")]
+pub struct AgentSubCmd {
+    #[argh(option, short = 'i')]
+    /// identity to unlock, repeatable
+    identity: Vec<String>,
+    #[argh(option)]
+    /// socket path, default $VAULTIX_AGENT_SOCK or under $XDG_RUNTIME_DIR
+    socket: Option<String>,
+    #[argh(option, default = "3600")]
+    /// exit after idle for seconds
+    timeout: u64,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Manage identity files
+#[argh(subcommand, name = "identity")]
+pub struct IdentitySubCmd {
+    #[argh(subcommand)]
+    op: IdentityOp,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum IdentityOp {
+    Protect(IdentityProtectSubCmd),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Wrap plain identity file with passphrase, the same as `age -p -a`
+#[argh(subcommand, name = "protect")]
+pub struct IdentityProtectSubCmd {
+    #[argh(positional)]
+    /// identity file to protect
+    file: String,
+    #[argh(option, short = 'o')]
+    /// write to this path instead of replacing the file
+    output: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Inspect profiles
+#[argh(subcommand, name = "profile")]
+pub struct ProfileSubCmd {
+    #[argh(subcommand)]
+    op: ProfileOp,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum ProfileOp {
+    Validate(ProfileValidateSubCmd),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Migrate and print every schema violation of profiles
+#[argh(subcommand, name = "validate")]
+pub struct ProfileValidateSubCmd {
+    #[argh(positional)]
+    /// profiles to validate, default those given by `-p`
+    files: Vec<String>,
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -76,20 +373,30 @@ pub struct CheckSubCmd {}
 impl Args {
     /// Parse Command Args
     pub fn ayaya(&self) -> eyre::Result<()> {
-        use super::profile::Profile;
+        use crate::profile::Profile;
+
+        let passphrase = match (
+            self.passphrase_fd,
+            &self.passphrase_env,
+            &self.passphrase_file,
+        ) {
+            (None, None, None) => None,
+            (Some(fd), None, None) => Some(PassphraseSource::Fd(fd)),
+            (None, Some(e), None) => Some(PassphraseSource::Env(e.clone())),
+            (None, None, Some(f)) => Some(PassphraseSource::File(f.into())),
+            _ => eyre::bail!("only one of passphrase fd, env or file could be given"),
+        };
+        set_interaction(Interaction {
+            no_interactive: self.no_interactive,
+            passphrase,
+        });
 
         let profile = || -> eyre::Result<Vec<Profile>> {
-            self.profile
+            Ok(self
+                .profile
                 .iter()
-                .map(|p| {
-                    fs::read_to_string(p)
-                        .wrap_err_with(|| eyre!("read file error"))
-                        .and_then(|f| {
-                            serde_json::from_str(f.as_str())
-                                .wrap_err_with(|| eyre::eyre!("parse profile fail"))
-                        })
-                })
-                .collect()
+                .map(Profile::from_file)
+                .collect::<crate::Result<_>>()?)
         };
 
         let flake_root = if let Some(f) = &self.flake_root {
@@ -99,27 +406,140 @@ impl Args {
         };
 
         match &self.app {
-            SubCmd::Renc(RencSubCmd { identity, cache }) => {
+            SubCmd::Renc(RencSubCmd {
+                identity,
+                cache,
+                host,
+            }) => {
                 info!("start re-encrypt secrets");
                 let profile = profile()?;
-                CompleteProfile::from_iter(&profile).renc(
+                let ParsedIdentity { identity, .. } = RawIdentity::from(identity.clone())
+                    .try_into()
+                    .map_err(crate::Error::Identity)?;
+                let report = CompleteProfile::from_iter(&profile).renc(
                     flake_root,
-                    identity.clone(),
+                    identity.as_ref(),
                     cache.into(),
-                )
+                    host.clone(),
+                )?;
+                info!(
+                    "{} cache(s) written, {} outdated removed",
+                    report.written.len(),
+                    report.removed.len()
+                );
+                if !report.failed.is_empty() {
+                    eyre::bail!("{} cache(s) failed to write", report.failed.len());
+                }
+                Ok(())
             }
-            SubCmd::Deploy(DeploySubCmd { early }) => {
-                info!("deploying secrets");
+            SubCmd::Deploy(DeploySubCmd {
+                early,
+                phase,
+                cache,
+                root,
+                no_mount,
+                map_user,
+                map_group,
+            }) => {
+                use crate::profile::{PHASE_DEFAULT, PHASE_EARLY};
+                let phase = match (early, phase) {
+                    (true, Some(_)) => eyre::bail!("`--early` conflicts with `--phase`"),
+                    (true, None) => PHASE_EARLY,
+                    (false, Some(p)) => p.as_str(),
+                    (false, None) => PHASE_DEFAULT,
+                };
+                info!("deploying secrets of phase {}", phase);
+                let mut profile = profile()?;
+                if let Some(c) = cache {
+                    profile.iter_mut().for_each(|p| p.with_cache_dir(c));
+                }
+                let opts = deploy::DeployOptions {
+                    root: root.as_ref().map(PathBuf::from),
+                    no_mount: *no_mount,
+                    users: id_map(map_user)?,
+                    groups: id_map(map_group)?,
+                };
+                CompleteProfile::from_iter(&profile).deploy_with(phase, &opts)?;
+                Ok(())
+            }
+            SubCmd::Undeploy(UndeploySubCmd { root }) => {
+                info!("undeploying secrets");
+                let profile = profile()?;
+                let report = CompleteProfile::from_iter(&profile)
+                    .undeploy_with(root.as_deref().map(Path::new))?;
+                if !report.failed.is_empty() {
+                    eyre::bail!("{} item(s) failed to remove", report.failed.len());
+                }
+                Ok(())
+            }
+            SubCmd::Verify(VerifySubCmd { repair }) => {
+                info!("verifying deployed secrets");
                 let profile = profile()?;
-                profile
-                    .first()
-                    .wrap_err_with(|| eyre!("deploy must provide one single profile"))?
-                    .deploy(*early)
+                let report = CompleteProfile::from_iter(&profile).verify(*repair)?;
+                if !report.drifts.is_empty() || !report.failed.is_empty() {
+                    eyre::bail!(
+                        "{} drift(s) left, {} failure(s)",
+                        report.drifts.len(),
+                        report.failed.len()
+                    );
+                }
+                Ok(())
+            }
+            SubCmd::Serve(s) => {
+                info!("starting secrets server");
+                let mut profile = profile()?;
+                if let Some(c) = &s.cache {
+                    profile.iter_mut().for_each(|p| p.with_cache_dir(c));
+                }
+                serve::serve(&CompleteProfile::from_iter(&profile), s)
+            }
+            SubCmd::Fetch(f) => serve::fetch(f),
+            SubCmd::Exec(e) => {
+                let mut profile = profile()?;
+                if let Some(c) = &e.cache {
+                    profile.iter_mut().for_each(|p| p.with_cache_dir(c));
+                }
+                exec::exec(&CompleteProfile::from_iter(&profile), e)
+            }
+            SubCmd::Shell(s) => dev::shell(s),
+            SubCmd::Env(e) => {
+                // stdout is eval'd, where logger writes too
+                log::set_max_level(log::LevelFilter::Off);
+                dev::env(e)
             }
             SubCmd::Edit(e) => {
                 info!("editing secrets");
                 edit::edit(e.clone())
             }
+            SubCmd::Host(HostSubCmd {
+                op: HostOp::Rotate(r),
+            }) => {
+                info!("start rotating host key");
+                host::rotate(&profile()?, flake_root, r)
+            }
+            SubCmd::Host(HostSubCmd {
+                op: HostOp::Import(i),
+            }) => {
+                info!("importing host keys");
+                host::import(&profile()?, i)
+            }
+            SubCmd::Host(HostSubCmd {
+                op: HostOp::Verify(v),
+            }) => host::verify(&profile()?, v),
+            SubCmd::Agent(a) => {
+                info!("starting identity agent");
+                agent::agent(a)
+            }
+            SubCmd::Identity(IdentitySubCmd {
+                op: IdentityOp::Protect(p),
+            }) => identity::protect(p),
+            SubCmd::Profile(ProfileSubCmd {
+                op: ProfileOp::Validate(ProfileValidateSubCmd { files }),
+            }) => profile::validate(if files.is_empty() {
+                &self.profile
+            } else {
+                files
+            }),
             SubCmd::Check(_) => {
                 info!("start checking");
                 let profile = profile()?;
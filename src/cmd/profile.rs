@@ -0,0 +1,25 @@
+use std::{fs, path::Path};
+
+use eyre::{Context, Result, bail, eyre};
+use log::info;
+
+use crate::profile::{Format, Profile};
+
+/// print every schema violation of each profile, fail if any
+pub fn validate(files: &[String]) -> Result<()> {
+    let mut total = 0;
+    for f in files {
+        let content = fs::read_to_string(f).wrap_err_with(|| eyre!("read file error: {}", f))?;
+        let mut value = Profile::read_value(content.as_str(), Format::from_path(Path::new(f)))?;
+        let errs = Profile::check_value(&mut value);
+        errs.iter().for_each(|e| println!("{}: {}", f, e));
+        if errs.is_empty() {
+            info!("{}: ok", f);
+        }
+        total += errs.len();
+    }
+    if total > 0 {
+        bail!("{} problem(s) found in profiles", total);
+    }
+    Ok(())
+}
@@ -1,13 +1,29 @@
 use crate::{
-    parser::identity::{ParsedIdentity, RawIdentity},
+    error::{Error, Result},
+    parser::glob_match,
     profile::Profile,
     util::secmap::{RencBuilder, RencCtx},
 };
-use eyre::{Result, bail};
+use age::Identity;
 use log::error;
 use std::{fs, path::PathBuf};
 
-pub struct CompleteProfile<'a>(pub Vec<&'a Profile>);
+/// `.vaultix` marks repo root when not managed by flake
+const ROOT_MARKERS: [&str; 2] = ["flake.nix", ".vaultix"];
+
+/// outcome of [`CompleteProfile::renc`]
+#[derive(Debug, Default)]
+pub struct RencReport {
+    /// cache files written
+    pub written: Vec<PathBuf>,
+    /// outdated cache files removed
+    pub removed: Vec<PathBuf>,
+    /// cache files failed to write, already logged
+    pub failed: Vec<eyre::Report>,
+}
+
+/// profiles deployed or re-encrypted together, collected from iterator
+pub struct CompleteProfile<'a>(Vec<&'a Profile>);
 
 impl<'a> FromIterator<&'a Profile> for CompleteProfile<'a> {
     fn from_iter<T: IntoIterator<Item = &'a Profile>>(iter: T) -> Self {
@@ -20,9 +36,6 @@ impl<'a> FromIterator<&'a Profile> for CompleteProfile<'a> {
 }
 
 impl<'a> CompleteProfile<'a> {
-    pub fn _inner(self) -> Vec<&'a Profile> {
-        self.0
-    }
     pub fn inner_ref(&self) -> &Vec<&Profile> {
         &self.0
     }
@@ -30,34 +43,54 @@ impl<'a> CompleteProfile<'a> {
     /**
     read secret metadata from profile
 
-    First decrypt `./secrets/every` with masterIdentity's privkey,
+    First decrypt `./secrets/every` with `identity`, the master identity,
     Then compare hash with decrypted existing file (using hostKey),
     encrypt with host public key, output to `./secrets/renced/$host`
     and add to nix store.
+
+    `hosts` limits the work to matching host identifiers, others' caches
+    are left untouched.
     */
-    pub fn renc(self, flake_root: PathBuf, identity: String, cache_path: PathBuf) -> Result<()> {
-        // check if flake root
+    pub fn renc(
+        self,
+        flake_root: PathBuf,
+        identity: &dyn Identity,
+        cache_path: PathBuf,
+        hosts: Vec<String>,
+    ) -> Result<RencReport> {
+        // check if flake root, or marked root of standalone repo
         if !fs::read_dir(&flake_root)?.any(|e| {
             e.is_ok_and(|ie| {
                 ie.file_name()
                     .into_string()
-                    .is_ok_and(|iie| iie.as_str() == "flake.nix")
+                    .is_ok_and(|iie| ROOT_MARKERS.contains(&iie.as_str()))
             })
         }) {
-            error!("please run app in flake root");
-            bail!("`flake.nix` not found here, make sure run in flake toplevel.");
+            error!("please run app in flake root, or a directory containing `.vaultix`");
+            return Err(Error::NotRepoRoot(flake_root));
         };
 
+        if let Some(p) = hosts.iter().find(|p| {
+            !self
+                .inner_ref()
+                .iter()
+                .any(|i| glob_match(p, i.host_identifier()))
+        }) {
+            return Err(Error::NoHostMatch(p.clone()));
+        }
+
         let ctx = RencCtx::create(&self)?;
-        let mut materia = RencBuilder::create(&self).build_inrepo(&ctx, cache_path.clone());
-        materia.clean_outdated(cache_path)?;
+        let mut materia = RencBuilder::create(&self)
+            .retain_hosts(&hosts)
+            .build_inrepo(&ctx, cache_path.clone());
+        let removed = materia.clean_outdated(cache_path)?;
         materia.retain_noexist();
 
-        let ParsedIdentity {
-            identity,
-            recipient: _,
-        } = RawIdentity::from(identity).try_into()?;
-
-        materia.build_instance().makeup(&ctx, identity)
+        let (written, failed) = materia.build_instance().makeup(&ctx, identity)?;
+        Ok(RencReport {
+            written,
+            removed,
+            failed,
+        })
     }
 }
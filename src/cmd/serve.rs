@@ -0,0 +1,354 @@
+use std::{
+    collections::HashMap,
+    fs::{self, DirBuilder, File, OpenOptions},
+    io::{self, Write},
+    iter,
+    os::unix::{
+        fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::Mutex,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use age::secrecy::{ExposeSecret, SecretSlice};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use eyre::{Context, ContextCompat, Result, bail, eyre};
+use log::{debug, info, warn};
+use serde::Serialize;
+
+use crate::{
+    cmd::renc::CompleteProfile,
+    profile::{Secret, ServePolicy},
+    util::{
+        agent::{
+            MAX_CONNECTIONS, Slots, peer_cred, peer_groups, peer_pidfd, pidfd_alive, read_message,
+            write_message,
+        },
+        serve::{Request, Response, SOCKET_ENV, request, socket_path},
+        set_owner_group::{resolve_gid, resolve_uid},
+    },
+};
+
+use super::{FetchSubCmd, ServeSubCmd};
+
+/// ids and executables looked up once at start, unknown names never match
+#[derive(Debug)]
+struct Policy {
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+    executables: Vec<PathBuf>,
+}
+
+impl Policy {
+    fn new(id: &str, s: &Secret, p: &ServePolicy) -> Self {
+        let known = |res: Result<u32>| res.inspect_err(|e| warn!("policy of {}: {:#}", id, e)).ok();
+        Self {
+            uids: iter::once(&s.owner)
+                .chain(&p.users)
+                .filter_map(|u| known(resolve_uid(u)))
+                .collect(),
+            gids: iter::once(&s.group)
+                .chain(&p.groups)
+                .filter_map(|g| known(resolve_gid(g)))
+                .collect(),
+            // /proc/<pid>/exe is always resolved
+            executables: p
+                .executables
+                .iter()
+                .map(|e| fs::canonicalize(e).unwrap_or_else(|_| e.into()))
+                .collect(),
+        }
+    }
+
+    /// `gids` are primary and supplementary groups of peer
+    fn allows(&self, uid: u32, gids: &[u32], exe: Option<&Path>) -> bool {
+        (self.uids.contains(&uid) || gids.iter().any(|g| self.gids.contains(g)))
+            && (self.executables.is_empty()
+                || exe.is_some_and(|e| self.executables.iter().any(|x| x == e)))
+    }
+}
+
+/// executable of peer, none unless its pidfd proves the pid still is it
+fn peer_exe(stream: &UnixStream, pid: i32) -> Option<PathBuf> {
+    let pidfd = peer_pidfd(stream)
+        .inspect_err(|e| warn!("pidfd of peer {}: {}, executables never match", pid, e))
+        .ok()?;
+    let exe = fs::read_link(format!("/proc/{}/exe", pid)).ok()?;
+    // read before the peer exited, not of whoever reused its pid
+    pidfd_alive(&pidfd).then_some(exe)
+}
+
+struct Served {
+    content: SecretSlice<u8>,
+    policy: Policy,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time: u64,
+    pid: i32,
+    uid: u32,
+    gid: u32,
+    exe: Option<&'a Path>,
+    id: &'a str,
+    granted: bool,
+}
+
+struct Server {
+    secrets: HashMap<String, Served>,
+    /// json lines appended, to log if none
+    audit: Option<Mutex<File>>,
+}
+
+impl Server {
+    fn audit(&self, record: &AuditRecord) {
+        let line = serde_json::to_string(record).expect("plain record");
+        match &self.audit {
+            Some(f) => {
+                let res = f
+                    .lock()
+                    .map_err(|_| io::Error::other("audit log poisoned"))
+                    .and_then(|mut f| writeln!(f, "{}", line));
+                if let Err(e) = res {
+                    warn!("write audit log error: {}, record: {}", e, line);
+                }
+            }
+            None => info!(target: "vaultix::audit", "{}", line),
+        }
+    }
+
+    fn handle(&self, stream: UnixStream) -> Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        // all taken before reading request, which the peer may delay
+        let cred = peer_cred(&stream)?;
+        let mut gids = peer_groups(&stream)?;
+        gids.push(cred.gid);
+        let exe = peer_exe(&stream, cred.pid);
+
+        let id = match read_message::<Request>(&stream) {
+            Ok(Request::Get(id)) => id,
+            Err(e) => {
+                let _ = write_message(&stream, &Response::Error("malformed request".into()));
+                return Err(e).wrap_err("read request error");
+            }
+        };
+
+        let found = self
+            .secrets
+            .get(&id)
+            .filter(|s| s.policy.allows(cred.uid, &gids, exe.as_deref()));
+        self.audit(&AuditRecord {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            pid: cred.pid,
+            uid: cred.uid,
+            gid: cred.gid,
+            exe: exe.as_deref(),
+            id: id.as_str(),
+            granted: found.is_some(),
+        });
+        let resp = match found {
+            Some(s) => Response::Secret(STANDARD.encode(s.content.expose_secret())),
+            None => Response::Denied,
+        };
+        write_message(&stream, &resp)?;
+        Ok(())
+    }
+}
+
+/// connectable by everyone, peers are told apart by credentials
+fn prepare_socket(path: &Path) -> Result<UnixListener> {
+    let dir = path
+        .parent()
+        .with_context(|| eyre!("socket path has no parent"))?;
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(dir)
+        .wrap_err_with(|| eyre!("create serve socket dir error"))?;
+
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            bail!("already serving at {}", path.display());
+        }
+        debug!("removing stale socket {}", path.display());
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)
+        .wrap_err_with(|| eyre!("bind serve socket error: {}", path.display()))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o666))?;
+    Ok(listener)
+}
+
+impl CompleteProfile<'_> {
+    /**
+    Decrypt secrets having `serve` policy and hand them out on a unix socket
+
+    Each request is authorized by credentials of the connecting process,
+    and recorded to audit log whether granted or not. Up to
+    [`MAX_CONNECTIONS`] are handled at once. Runs until killed.
+    */
+    pub fn serve(&self, socket: &Path, audit_log: Option<&Path>) -> crate::Result<()> {
+        self.ensure_mergeable()?;
+        let mut secrets = HashMap::new();
+        for p in self.inner_ref() {
+            let served: Vec<_> = p
+                .secrets
+                .iter()
+                .filter_map(|(id, s)| Some((id, s, s.serve.as_ref()?)))
+                .collect();
+            if served.is_empty() {
+                continue;
+            }
+            let mut plain = p.decrypt()?;
+            for (id, s, policy) in served {
+                let content = plain
+                    .remove(&s.id)
+                    .wrap_err_with(|| eyre!("decrypted content of {} not found", id))?;
+                secrets.insert(
+                    id.clone(),
+                    Served {
+                        content: content.into(),
+                        policy: Policy::new(id, s, policy),
+                    },
+                );
+            }
+        }
+        if secrets.is_empty() {
+            warn!("no secret has `serve` policy, serving nothing");
+        }
+
+        let audit = audit_log
+            .map(|p| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .mode(0o600)
+                    .open(p)
+                    .map(Mutex::new)
+                    .wrap_err_with(|| eyre!("open audit log error: {}", p.display()))
+            })
+            .transpose()?;
+        let server = Server { secrets, audit };
+        let listener = prepare_socket(socket)?;
+        info!(
+            "serving {} secret(s) on {}",
+            server.secrets.len(),
+            socket.display()
+        );
+
+        let slots = Slots::default();
+        thread::scope(|s| {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let Some(slot) = slots.take() else {
+                            warn!("{} connections in progress, refused one", MAX_CONNECTIONS);
+                            continue;
+                        };
+                        let server = &server;
+                        s.spawn(move || {
+                            if let Err(e) = server.handle(stream) {
+                                warn!("{:#}", e);
+                            }
+                            drop(slot);
+                        });
+                    }
+                    Err(e) => warn!("accept connection error: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+pub fn serve(profiles: &CompleteProfile, arg: &ServeSubCmd) -> Result<()> {
+    let socket = arg.socket.as_ref().map_or_else(socket_path, PathBuf::from);
+    info!("export {}={}", SOCKET_ENV, socket.display());
+    Ok(profiles.serve(&socket, arg.audit_log.as_deref().map(Path::new))?)
+}
+
+/// print a served secret to stdout, as is
+pub fn fetch(arg: &FetchSubCmd) -> Result<()> {
+    let socket = arg.socket.as_ref().map_or_else(socket_path, PathBuf::from);
+    let resp = request(&socket, &Request::Get(arg.id.clone()))
+        .wrap_err_with(|| eyre!("request {} error", socket.display()))?;
+    match resp {
+        Response::Secret(s) => {
+            let content = STANDARD.decode(s).wrap_err("malformed response")?;
+            io::stdout().write_all(&content)?;
+            Ok(())
+        }
+        Response::Denied => bail!("{} not served to this process", arg.id),
+        Response::Error(e) => bail!("serve error: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policy_match() {
+        let policy = Policy {
+            uids: vec![0, 1000],
+            gids: vec![100],
+            executables: vec![],
+        };
+        assert!(policy.allows(1000, &[1000], None));
+        assert!(policy.allows(1001, &[100], None));
+        assert!(policy.allows(1001, &[1001, 100], None));
+        assert!(!policy.allows(1001, &[1001], None));
+
+        let pinned = Policy {
+            executables: vec!["/nix/store/xxx-nginx/bin/nginx".into()],
+            ..policy
+        };
+        assert!(pinned.allows(
+            1000,
+            &[1000],
+            Some(Path::new("/nix/store/xxx-nginx/bin/nginx"))
+        ));
+        assert!(!pinned.allows(1000, &[1000], Some(Path::new("/usr/bin/cat"))));
+        assert!(!pinned.allows(1000, &[1000], None));
+    }
+
+    #[test]
+    fn handle_requests() {
+        let served = |uids| Served {
+            content: b"hunter2".to_vec().into(),
+            policy: Policy {
+                uids,
+                gids: vec![],
+                executables: vec![],
+            },
+        };
+        let server = Server {
+            secrets: HashMap::from([
+                ("mine".to_string(), served(vec![unsafe { libc::getuid() }])),
+                ("other".to_string(), served(vec![])),
+            ]),
+            audit: None,
+        };
+        let ask = |line: &[u8]| {
+            let (mut client, stream) = UnixStream::pair().unwrap();
+            client.write_all(line).unwrap();
+            let res = server.handle(stream);
+            (res, read_message::<Response>(&client).unwrap())
+        };
+
+        match ask(b"{\"Get\":\"mine\"}\n") {
+            (Ok(()), Response::Secret(s)) => assert_eq!(STANDARD.decode(s).unwrap(), b"hunter2"),
+            r => panic!("unexpected {:?}", r.1),
+        }
+        for id in ["other", "unknown"] {
+            let line = format!("{{\"Get\":\"{}\"}}\n", id);
+            assert!(matches!(ask(line.as_bytes()), (Ok(()), Response::Denied)));
+        }
+        assert!(matches!(ask(b"nonsense\n"), (Err(_), Response::Error(_))));
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+use eyre::{Result, eyre};
+use log::{debug, error, info, warn};
+use sys_mount::{UnmountFlags, unmount};
+
+use crate::{
+    cmd::renc::CompleteProfile,
+    profile::{PHASE_DEFAULT, PHASE_EARLY, Profile},
+    util::{
+        keyring,
+        manifest::{Manifest, fingerprint, generations},
+        set_owner_group::PENDING_OWNERS,
+    },
+};
+
+/// outcome of [`CompleteProfile::undeploy`]
+#[derive(Debug, Default)]
+pub struct UndeployReport {
+    /// files wiped and removed
+    pub removed: Vec<PathBuf>,
+    /// symlinks to generations removed
+    pub unlinked: Vec<PathBuf>,
+    /// kernel keys invalidated, by their `%type:description`
+    pub invalidated: Vec<PathBuf>,
+    /// secrets filesystem unmounted
+    pub unmounted: bool,
+    /// those failed to remove, already logged
+    pub failed: Vec<eyre::Report>,
+}
+
+/// overwrite with zeros before unlinking, never follows symlink
+pub(super) fn wipe(path: &Path) -> Result<()> {
+    let len = fs::symlink_metadata(path)?.len();
+    let mut f = OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+    io::copy(&mut io::repeat(0).take(len), &mut f)?;
+    f.flush()?;
+    f.sync_all()?;
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+fn read_nofollow(path: &Path) -> io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?
+        .read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// on another filesystem than its parent
+fn mounted(path: &Path) -> bool {
+    let dev = |p: &Path| fs::metadata(p).map(|m| m.dev());
+    match (dev(path), path.parent().map(dev)) {
+        (Ok(d), Some(Ok(parent))) => d != parent,
+        _ => false,
+    }
+}
+
+impl CompleteProfile<'_> {
+    /**
+    Remove everything deploy placed on this host
+
+    Files listed in manifests of every generation are wiped and removed,
+    and kernel keys invalidated. Those of custom `path` only while their
+    content matches a fingerprint in manifest, otherwise the file there is
+    someone else's now. Links pointing into mount point are removed, then
+    the secrets filesystem is unmounted.
+    */
+    pub fn undeploy(&self) -> crate::Result<UndeployReport> {
+        self.undeploy_with(None)
+    }
+
+    /// undeploy what `deploy --root` placed under a staging root
+    pub fn undeploy_with(&self, root: Option<&Path>) -> crate::Result<UndeployReport> {
+        let Some(root) = root else {
+            return self.undeploy_under(None);
+        };
+        let rooted: Vec<Profile> = self
+            .inner_ref()
+            .iter()
+            .map(|p| {
+                let mut p = (*p).clone();
+                p.with_root(root);
+                p
+            })
+            .collect();
+        CompleteProfile::from_iter(&rooted).undeploy_under(Some(root))
+    }
+
+    fn undeploy_under(&self, root: Option<&Path>) -> crate::Result<UndeployReport> {
+        let first = self.ensure_mergeable()?;
+        let mount_point = Path::new(first.decrypted_mount_point());
+        let mut report = UndeployReport::default();
+        // manifests record where items live once staging root becomes `/`
+        let on_host = |p: PathBuf| match root {
+            Some(r) => r.join(p.strip_prefix("/").unwrap_or(&p)),
+            None => p,
+        };
+
+        let mut links: BTreeSet<PathBuf> = self
+            .inner_ref()
+            .iter()
+            .flat_map(|p| {
+                [PHASE_EARLY, PHASE_DEFAULT]
+                    .into_iter()
+                    .chain(p.phases.keys().map(String::as_str))
+                    .filter_map(|ph| p.phase_link(ph))
+                    .map(PathBuf::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let mut files = BTreeSet::new();
+        // custom paths, only removed while still holding what deploy wrote
+        let mut outside: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+        let mut keys = BTreeSet::new();
+        let gens = generations(mount_point)?;
+        for (generation, dir) in gens.iter() {
+            match Manifest::read(dir)? {
+                Some(m) => {
+                    links.insert(on_host(m.link));
+                    m.items.into_iter().for_each(|i| match i.serial {
+                        Some(serial) => {
+                            keys.insert((serial, i.path));
+                        }
+                        None => {
+                            let path = on_host(i.path);
+                            if path.starts_with(mount_point) {
+                                files.insert(path);
+                            } else {
+                                outside.entry(path).or_default().push(i.fingerprint);
+                            }
+                        }
+                    });
+                }
+                None => warn!(
+                    "generation {} has no manifest, its custom paths are unknown",
+                    generation
+                ),
+            }
+            // anything else left in generation dir
+            fs::read_dir(dir)?.filter_map(|en| en.ok()).for_each(|en| {
+                files.insert(en.path());
+            });
+        }
+
+        for link in links {
+            let points_in = fs::read_link(&link).is_ok_and(|t| on_host(t).starts_with(mount_point));
+            if !points_in {
+                continue;
+            }
+            match fs::remove_file(&link) {
+                Ok(()) => {
+                    info!("unlinked {}", link.display());
+                    report.unlinked.push(link);
+                }
+                Err(e) => {
+                    let e = eyre!(e).wrap_err(format!("unlink {}", link.display()));
+                    error!("{:#}", e);
+                    report.failed.push(e);
+                }
+            }
+        }
+
+        for (serial, key) in keys {
+            match keyring::invalidate(serial) {
+                Ok(true) => {
+                    info!("invalidated key {}", key.display());
+                    report.invalidated.push(key);
+                }
+                Ok(false) => debug!("key {} already gone", key.display()),
+                Err(e) => {
+                    error!("{:#}", e);
+                    report.failed.push(e);
+                }
+            }
+        }
+
+        let key = first
+            .manifest_key()
+            .inspect_err(|e| warn!("{:#}, files of custom path can't be told apart", e))
+            .ok();
+        for (f, prints) in outside {
+            let ours = || {
+                key.as_ref().is_some_and(|k| {
+                    read_nofollow(&f).is_ok_and(|c| prints.contains(&fingerprint(k, &c)))
+                })
+            };
+            // gone and irregular ones are reported below
+            if fs::symlink_metadata(&f).is_ok_and(|m| m.is_file()) && !ours() {
+                warn!(
+                    "{} changed since deployed, not ours anymore, left untouched",
+                    f.display()
+                );
+                continue;
+            }
+            files.insert(f);
+        }
+
+        for f in files {
+            match fs::symlink_metadata(&f) {
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Ok(m) if !m.is_file() => {
+                    warn!(
+                        "{} is no longer a regular file, left untouched",
+                        f.display()
+                    );
+                    continue;
+                }
+                _ => {}
+            }
+            match wipe(&f) {
+                Ok(()) => {
+                    debug!("wiped {}", f.display());
+                    report.removed.push(f);
+                }
+                Err(e) => {
+                    let e = e.wrap_err(format!("wipe {}", f.display()));
+                    error!("{:#}", e);
+                    report.failed.push(e);
+                }
+            }
+        }
+
+        for (_, dir) in gens {
+            if let Err(e) = fs::remove_dir(&dir) {
+                let e = eyre!(e).wrap_err(format!("remove generation {}", dir.display()));
+                error!("{:#}", e);
+                report.failed.push(e);
+            }
+        }
+        match fs::remove_file(mount_point.join(PENDING_OWNERS)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => report
+                .failed
+                .push(eyre!(e).wrap_err("remove pending owners record")),
+            _ => {}
+        }
+
+        // extracted onto whatever holds a staging root, nothing to unmount
+        if root.is_none() || mounted(mount_point) {
+            match unmount(mount_point, UnmountFlags::empty()) {
+                Ok(()) => report.unmounted = true,
+                // not mounted, or already gone
+                Err(e) if [Some(libc::EINVAL), Some(libc::ENOENT)].contains(&e.raw_os_error()) => {}
+                Err(e) => {
+                    return Err(eyre!(e)
+                        .wrap_err(format!("unmount {}", mount_point.display()))
+                        .into());
+                }
+            }
+        }
+        match fs::remove_dir(mount_point) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                report
+                    .failed
+                    .push(eyre!(e).wrap_err(format!("remove {}", mount_point.display())));
+            }
+            _ => {}
+        }
+        info!(
+            "undeployed: {} file(s) removed, {} key(s) invalidated, {} link(s) removed",
+            report.removed.len(),
+            report.invalidated.len(),
+            report.unlinked.len()
+        );
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use super::*;
+    use crate::cmd::deploy::tests::staged;
+
+    #[test]
+    fn undeploy_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (p, opts) = staged(tmp.path());
+        let root = tmp.path().join("root");
+
+        // custom path dropped from config since, then taken by someone else
+        let mut old = p.clone();
+        old.secrets.get_mut("token").unwrap().path = "/etc/app/old-token".into();
+        for p in [&old, &p] {
+            let report = CompleteProfile::from_iter(iter::once(p))
+                .deploy_with(PHASE_DEFAULT, &opts)
+                .unwrap();
+            assert!(report.failed.is_empty());
+        }
+        let foreign = root.join("etc/app/old-token");
+        fs::write(&foreign, "not a secret").unwrap();
+
+        let report = CompleteProfile::from_iter(iter::once(&p))
+            .undeploy_with(Some(&root))
+            .unwrap();
+        assert!(report.failed.is_empty());
+        assert!(!report.unmounted);
+
+        let mount_point = root.join("run/vaultix.d");
+        let removed: BTreeSet<PathBuf> = report.removed.into_iter().collect();
+        let expect: BTreeSet<PathBuf> = ["0", "1"]
+            .into_iter()
+            .flat_map(|g| ["db", "conf", ".manifest.json"].map(|f| mount_point.join(g).join(f)))
+            .chain([root.join("etc/app/token")])
+            .collect();
+        assert_eq!(removed, expect);
+        assert_eq!(report.unlinked, [root.join("run/vaultix")]);
+        assert!(fs::symlink_metadata(root.join("run/vaultix")).is_err());
+        assert_eq!(fs::read(&foreign).unwrap(), b"not a secret");
+        assert!(!mount_point.exists());
+    }
+}
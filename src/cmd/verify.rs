@@ -0,0 +1,493 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    fs::{self, OpenOptions, Permissions},
+    os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context, ContextCompat, Result, bail, eyre};
+use log::{error, info, warn};
+
+use crate::{
+    cmd::renc::CompleteProfile,
+    error::Error,
+    parser::parse_octal_str,
+    profile::{DeployFactor, KeyType, KeyringDest, Profile, Secret, Template},
+    util::{
+        keyring,
+        manifest::{ItemKind, Manifest, ManifestItem, fingerprint, generations},
+        secbuf::{Plain, SecBuf},
+        set_owner_group::{IdMap, resolve_gid, resolve_uid, set_owner_and_group},
+    },
+};
+
+/// one difference between live filesystem and what was deployed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Drift {
+    /// phase link missing or not pointing to a generation of it
+    Link {
+        link: PathBuf,
+        found: Option<PathBuf>,
+    },
+    /// in profile but not in current generation, redeploy needed
+    NotDeployed { phase: String, id: String },
+    /// gone or no longer a regular file
+    Missing(PathBuf),
+    Mode {
+        path: PathBuf,
+        expected: u32,
+        found: u32,
+    },
+    Owner {
+        path: PathBuf,
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+    /// fingerprint differs from manifest
+    Content(PathBuf),
+    /// permission mask of kernel key
+    Perm {
+        path: PathBuf,
+        expected: u32,
+        found: u32,
+    },
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Link { link, found } => match found {
+                Some(t) => write!(f, "{} links to {}", link.display(), t.display()),
+                None => write!(f, "{} is not a link", link.display()),
+            },
+            Self::NotDeployed { phase, id } => {
+                write!(f, "{} not deployed in current generation of {}", id, phase)
+            }
+            Self::Missing(p) => write!(f, "{} missing", p.display()),
+            Self::Mode {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} mode {:o}, expect {:o}",
+                path.display(),
+                found,
+                expected
+            ),
+            Self::Owner {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} owned by {}:{}, expect {}:{}",
+                path.display(),
+                found.0,
+                found.1,
+                expected.0,
+                expected.1
+            ),
+            Self::Content(p) => write!(f, "{} content changed", p.display()),
+            Self::Perm {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} permission {:08x}, expect {:08x}",
+                path.display(),
+                found,
+                expected
+            ),
+        }
+    }
+}
+
+/// outcome of [`CompleteProfile::verify`]
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// drifts found and left as is
+    pub drifts: Vec<Drift>,
+    /// drifts fixed in place
+    pub repaired: Vec<Drift>,
+    /// those failed to check or repair, already logged
+    pub failed: Vec<eyre::Report>,
+}
+
+#[derive(Clone, Copy)]
+enum Item<'a> {
+    Secret(&'a Secret),
+    Template(&'a Template),
+}
+
+impl Item<'_> {
+    fn factor(&self) -> &dyn DeployFactor {
+        match self {
+            Self::Secret(s) => s,
+            Self::Template(t) => t,
+        }
+    }
+
+    fn keyring(&self) -> Option<&KeyringDest> {
+        match self {
+            Self::Secret(s) => s.keyring.as_ref(),
+            Self::Template(_) => None,
+        }
+    }
+}
+
+/// deployed content, checked against manifest before written back
+fn content_of(
+    profile: &Profile,
+    item: Item,
+    id: &str,
+    plain: &mut Option<HashMap<String, Vec<u8>>>,
+) -> Result<Vec<u8>> {
+    if plain.is_none() {
+        *plain = Some(profile.decrypt()?);
+    }
+    let plain = plain.as_ref().expect("decrypted above");
+    Ok(match item {
+        Item::Secret(_) => plain
+            .get(id)
+            .cloned()
+            .wrap_err_with(|| eyre!("decrypted content of {} not found", id))?,
+        Item::Template(t) => profile.render(t, plain)?.into_bytes(),
+    })
+}
+
+/// drifts of one deployed file, in the order they are repaired
+fn inspect(item: Item, entry: &ManifestItem, key: &[u8; 32]) -> Result<Vec<Drift>> {
+    let path = entry.path.clone();
+    let meta = match fs::symlink_metadata(&path) {
+        Ok(m) if m.is_file() => m,
+        _ => return Ok(vec![Drift::Missing(path)]),
+    };
+    let f = item.factor();
+    let mut drifts = Vec::new();
+    if fingerprint(key, &fs::read(&path)?) != entry.fingerprint {
+        drifts.push(Drift::Content(path.clone()));
+    }
+    // group class bits hold ACL mask once entries added
+    let bits = if f.acl().is_empty() { 0o777 } else { 0o707 };
+    let expected = parse_octal_str(f.mode()).map_err(|e| eyre!(e))? & bits;
+    if meta.mode() & bits != expected {
+        drifts.push(Drift::Mode {
+            path: path.clone(),
+            expected,
+            found: meta.mode() & 0o777,
+        });
+    }
+    let expected = (resolve_uid(f.owner())?, resolve_gid(f.group())?);
+    if (meta.uid(), meta.gid()) != expected {
+        drifts.push(Drift::Owner {
+            path,
+            expected,
+            found: (meta.uid(), meta.gid()),
+        });
+    }
+    Ok(drifts)
+}
+
+/// drifts of one kernel key, looked up by description in its keyring
+fn inspect_key(
+    dest: &KeyringDest,
+    f: &dyn DeployFactor,
+    entry: &ManifestItem,
+    key: &[u8; 32],
+) -> Result<Vec<Drift>> {
+    let path = entry.path.clone();
+    let expected = (resolve_uid(f.owner())?, resolve_gid(f.group())?);
+    let Some(serial) = keyring::find(dest, expected.0)? else {
+        return Ok(vec![Drift::Missing(path)]);
+    };
+    let info = keyring::describe(serial)?;
+    let mut drifts = Vec::new();
+    // logon keys are never read back
+    if dest.r#type == KeyType::User
+        && fingerprint(key, &keyring::read(serial)?) != entry.fingerprint
+    {
+        drifts.push(Drift::Content(path.clone()));
+    }
+    let perm = dest.perm_mask()?;
+    if info.perm != perm {
+        drifts.push(Drift::Perm {
+            path: path.clone(),
+            expected: perm,
+            found: info.perm,
+        });
+    }
+    if (info.uid, info.gid) != expected {
+        drifts.push(Drift::Owner {
+            path,
+            expected,
+            found: (info.uid, info.gid),
+        });
+    }
+    Ok(drifts)
+}
+
+impl CompleteProfile<'_> {
+    /**
+    Compare every phase link and deployed file with current generation
+
+    With `repair`, links are pointed back to newest generation of the
+    phase, and files are rewritten in place, never a new generation. Content
+    is only restored when it still matches the manifest fingerprint.
+    */
+    pub fn verify(&self, repair: bool) -> crate::Result<VerifyReport> {
+        let first = self.ensure_mergeable()?;
+        let mount_point = Path::new(first.decrypted_mount_point());
+        let profiles = self.inner_ref();
+        let mut report = VerifyReport::default();
+
+        let phases: BTreeSet<&str> = profiles
+            .iter()
+            .flat_map(|p| {
+                p.secrets
+                    .values()
+                    .filter(|s| s.deployed())
+                    .map(|s| s.phase.as_str())
+                    .chain(p.templates.values().map(|t| t.phase.as_str()))
+            })
+            .collect();
+        let gens = generations(mount_point)?;
+        let key = first.manifest_key().map_err(Error::Identity)?;
+        let mut plain: Vec<Option<HashMap<String, Vec<u8>>>> = vec![None; profiles.len()];
+
+        for phase in phases {
+            let Some(link) = profiles.iter().find_map(|p| p.phase_link(phase)) else {
+                continue;
+            };
+            let link = PathBuf::from(link);
+            let target = fs::read_link(&link).ok();
+            let (dir, mut manifest) = match target
+                .as_ref()
+                .filter(|t| gens.iter().any(|(_, d)| d == *t))
+                .map(|t| Manifest::read(t).map(|m| m.map(|m| (t.clone(), m))))
+                .transpose()?
+                .flatten()
+                .filter(|(_, m)| m.phase == phase)
+            {
+                Some(current) => current,
+                None => {
+                    let drift = Drift::Link {
+                        link: link.clone(),
+                        found: target,
+                    };
+                    warn!("{}", drift);
+                    // newest generation of this phase
+                    let newest = gens.iter().rev().find_map(|(_, d)| {
+                        Manifest::read(d)
+                            .ok()
+                            .flatten()
+                            .filter(|m| m.phase == phase)
+                            .map(|m| (d.clone(), m))
+                    });
+                    match newest.filter(|_| repair) {
+                        Some((dir, m)) => {
+                            let _ = fs::remove_file(&link);
+                            match std::os::unix::fs::symlink(&dir, &link) {
+                                Ok(()) => {
+                                    info!("relinked {} to {}", link.display(), dir.display());
+                                    report.repaired.push(drift);
+                                    (dir, m)
+                                }
+                                Err(e) => {
+                                    report.failed.push(eyre!(e).wrap_err("relink failed"));
+                                    report.drifts.push(drift);
+                                    continue;
+                                }
+                            }
+                        }
+                        None => {
+                            report.drifts.push(drift);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            // keys added again by repair, serials kept for undeploy
+            let mut renewed = Vec::new();
+            for (i, p) in profiles.iter().enumerate() {
+                let items = p
+                    .secrets
+                    .iter()
+                    .filter(|(_, s)| s.phase == phase && s.deployed())
+                    .map(|(id, s)| (ItemKind::Secret, id, Item::Secret(s)))
+                    .chain(
+                        p.templates
+                            .iter()
+                            .filter(|(_, t)| t.phase == phase)
+                            .map(|(id, t)| (ItemKind::Template, id, Item::Template(t))),
+                    );
+                for (kind, id, item) in items {
+                    let Some(entry) = manifest
+                        .items
+                        .iter()
+                        .find(|e| e.kind == kind && e.id == *id)
+                    else {
+                        let drift = Drift::NotDeployed {
+                            phase: phase.to_string(),
+                            id: id.clone(),
+                        };
+                        warn!("{}", drift);
+                        report.drifts.push(drift);
+                        continue;
+                    };
+                    let inspected = match item.keyring() {
+                        Some(dest) => inspect_key(dest, item.factor(), entry, &key),
+                        None => inspect(item, entry, &key),
+                    };
+                    let drifts = match inspected {
+                        Ok(d) => d,
+                        Err(e) => {
+                            let e = e.wrap_err(format!("verify {}", entry.path.display()));
+                            error!("{:#}", e);
+                            report.failed.push(e);
+                            continue;
+                        }
+                    };
+                    if drifts.is_empty() {
+                        continue;
+                    }
+                    drifts.iter().for_each(|d| warn!("{}", d));
+                    if !repair {
+                        report.drifts.extend(drifts);
+                        continue;
+                    }
+                    match restore(p, item, entry, &drifts, &key, &mut plain[i]) {
+                        Ok(serial) => {
+                            info!("repaired {}", entry.path.display());
+                            if serial.is_some() && serial != entry.serial {
+                                renewed.push((entry.id.clone(), serial));
+                            }
+                            report.repaired.extend(drifts);
+                        }
+                        Err(e) => {
+                            let e = e.wrap_err(format!("repair {}", entry.path.display()));
+                            error!("{:#}", e);
+                            report.failed.push(e);
+                            report.drifts.extend(drifts);
+                        }
+                    }
+                }
+            }
+            if !renewed.is_empty() {
+                manifest.items.iter_mut().for_each(|e| {
+                    if let Some((_, serial)) = renewed.iter().find(|(id, _)| *id == e.id) {
+                        e.serial = *serial;
+                    }
+                });
+                if let Err(e) = manifest.write(&dir) {
+                    error!("{:#}", e);
+                    report.failed.push(e);
+                }
+            }
+        }
+        info!(
+            "{} drift(s) found, {} repaired",
+            report.drifts.len() + report.repaired.len(),
+            report.repaired.len()
+        );
+        Ok(report)
+    }
+}
+
+/// rewrite content or fix metadata in place, keys are added again and
+/// their serial returned
+fn restore(
+    profile: &Profile,
+    item: Item,
+    entry: &ManifestItem,
+    drifts: &[Drift],
+    key: &[u8; 32],
+    plain: &mut Option<HashMap<String, Vec<u8>>>,
+) -> Result<Option<i32>> {
+    let f = item.factor();
+    if let Some(dest) = item.keyring() {
+        let content = content_of(profile, item, &entry.id, plain)?;
+        if fingerprint(key, &content) != entry.fingerprint {
+            bail!("secret changed since deployed, deploy a new generation instead");
+        }
+        let (uid, gid) = (resolve_uid(f.owner())?, resolve_gid(f.group())?);
+        return keyring::add(dest, &content, uid, gid).map(Some);
+    }
+    let rewrite = drifts
+        .iter()
+        .any(|d| matches!(d, Drift::Missing(_) | Drift::Content(_)));
+    if rewrite {
+        if fs::symlink_metadata(&entry.path).is_ok_and(|m| !m.is_file()) {
+            bail!("not a regular file, left untouched");
+        }
+        let content = content_of(profile, item, &entry.id, plain)?;
+        if fingerprint(key, &content) != entry.fingerprint {
+            bail!("secret changed since deployed, deploy a new generation instead");
+        }
+        let buf = SecBuf::<Plain>::new(content);
+        let dst = entry.path.clone();
+        match item {
+            Item::Secret(s) => buf.deploy_to_fs(s, dst, false, &IdMap::default()),
+            Item::Template(t) => buf.deploy_to_fs(t, dst, false, &IdMap::default()),
+        }?;
+        return Ok(None);
+    }
+
+    let file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&entry.path)?;
+    let mode = parse_octal_str(f.mode()).map_err(|e| eyre!(e))?;
+    file.set_permissions(Permissions::from_mode(mode))
+        .wrap_err("set permission failed")?;
+    set_owner_and_group(
+        &file,
+        &entry.path,
+        f.owner(),
+        f.group(),
+        f.acl(),
+        false,
+        &IdMap::default(),
+    )?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::manifest::fingerprint_key;
+
+    #[test]
+    fn inspect_drifts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("t");
+        fs::write(&path, "content").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o600)).unwrap();
+        let meta = fs::metadata(&path).unwrap();
+
+        let t = Template {
+            mode: "600".into(),
+            owner: meta.uid().to_string(),
+            group: meta.gid().to_string(),
+            ..Template::default()
+        };
+        let key = fingerprint_key(b"host key");
+        let entry = ManifestItem::new(ItemKind::Template, "t", &&t, path.clone(), &key, b"content");
+        let item = Item::Template(&t);
+        assert!(inspect(item, &entry, &key).unwrap().is_empty());
+
+        fs::write(&path, "edited").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        let drifts = inspect(item, &entry, &key).unwrap();
+        assert!(matches!(
+            drifts[..],
+            [Drift::Content(_), Drift::Mode { found: 0o644, .. }]
+        ));
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(inspect(item, &entry, &key).unwrap(), [Drift::Missing(path)]);
+    }
+}
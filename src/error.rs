@@ -0,0 +1,92 @@
+use std::{fmt, path::PathBuf};
+
+use crate::profile::schema::SchemaError;
+
+/// failures of library entry points
+#[derive(Debug)]
+pub enum Error {
+    /// profile unreadable or malformed
+    Profile(eyre::Report),
+    /// profile violates schema, every problem found
+    Schema(Vec<SchemaError>),
+    /// identity missing, locked or failed to decrypt
+    Identity(eyre::Report),
+    /// renc called outside of flake toplevel or `.vaultix` marked dir
+    NotRepoRoot(PathBuf),
+    /// host pattern given to renc matches nothing
+    NoHostMatch(String),
+    /// cache of a secret not found, renc needed
+    NotRenced(PathBuf),
+    /// cache not encrypted to one of extra recipients
+    MissingRecipient { cache: PathBuf, recipient: String },
+    /// profiles deployed together overlap or target different hosts
+    Conflict(String),
+    /// host key rotation recorded but not completed
+    Rotation(String),
+    /// owner, group or ACL entry of items doesn't exist
+    UnresolvedOwner(Vec<String>),
+    /// anything else, with its context chain
+    Other(eyre::Report),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Profile(e) => write!(f, "invalid profile: {:#}", e),
+            Self::Schema(errs) => {
+                write!(f, "profile schema violated:")?;
+                errs.iter().try_for_each(|e| write!(f, "\n  {}", e))
+            }
+            Self::Identity(e) => write!(f, "identity error: {:#}", e),
+            Self::NotRepoRoot(p) => write!(f, "no `flake.nix` or `.vaultix` in {}", p.display()),
+            Self::NoHostMatch(p) => write!(f, "no host matches `{}`", p),
+            Self::NotRenced(p) => write!(f, "secrets haven't been re-encrypted: {}", p.display()),
+            Self::MissingRecipient { cache, recipient } => write!(
+                f,
+                "cache {} not encrypted to extra recipient {}",
+                cache.display(),
+                recipient
+            ),
+            Self::Conflict(m) => write!(f, "conflicting profiles: {}", m),
+            Self::Rotation(m) => write!(f, "{}", m),
+            Self::UnresolvedOwner(items) => {
+                write!(f, "owner of deployed items can't be resolved:")?;
+                items.iter().try_for_each(|i| write!(f, "\n  {}", i))
+            }
+            Self::Other(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Self::Other(value.into())
+    }
+}
+
+/// typed errors raised deep inside survive `wrap_err`, others become `Other`
+impl From<eyre::Report> for Error {
+    fn from(value: eyre::Report) -> Self {
+        value.downcast::<Error>().unwrap_or_else(Self::Other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eyre::WrapErr;
+
+    #[test]
+    fn downcast_through_context() {
+        let r: eyre::Result<()> = Err(Error::NoHostMatch("web*".into()).into());
+        let e: Error = r.wrap_err("renc failed").unwrap_err().into();
+        assert!(matches!(e, Error::NoHostMatch(p) if p == "web*"));
+
+        let e: Error = eyre::eyre!("plain").into();
+        assert!(matches!(e, Error::Other(_)));
+    }
+}
@@ -1,7 +1,45 @@
 #![feature(iterator_try_collect)]
-mod parser {
-    pub mod permission;
-    pub mod template;
+/*!
+Vaultix as a library
+
+Everything the `vaultix` binary does goes through here. Read [`Profile`]s
+produced by the nixos module, then call [`CompleteProfile::renc`] with an
+[`age::Identity`] already at hand, [`CompleteProfile::check`] or
+[`Profile::deploy`]. Failures are reported as [`Error`]. [`Args`] is the
+command line itself.
+
+Prompts for passphrase follow [`set_interaction`], which defaults to
+asking on terminal or pinentry.
+*/
+mod cmd;
+pub mod error;
+mod parser;
+pub mod profile;
+mod util {
+    pub mod acl;
+    pub mod agent;
+    pub mod callback;
+    pub mod keyring;
+    pub mod makeup;
+    pub mod manifest;
+    pub mod registry;
+    pub mod rotation;
+    pub mod secbuf;
+    pub mod secfs;
+    pub mod secmap;
+    pub mod serve;
+    pub mod set_owner_group;
 }
-pub use parser::permission::parse_octal_str;
-pub use parser::template::extract_all_hashes;
+
+pub use cmd::{
+    Args,
+    deploy::{DeployOptions, DeployReport},
+    renc::CompleteProfile,
+    renc::RencReport,
+    undeploy::UndeployReport,
+    verify::{Drift, VerifyReport},
+};
+pub use error::{Error, Result};
+pub use parser::{extract_all_hashes, parse_octal_str};
+pub use profile::Profile;
+pub use util::callback::{Interaction, PassphraseSource, set_interaction};
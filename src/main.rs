@@ -1,19 +1,7 @@
-#![feature(iterator_try_collect)]
-use cmd::Args;
 use eyre::Result;
+use lib::Args;
 use simple_logger::SimpleLogger;
 
-mod cmd;
-mod util {
-    pub mod callback;
-    pub mod makeup;
-    pub mod secbuf;
-    pub mod secmap;
-    pub mod set_owner_group;
-}
-mod parser;
-mod profile;
-
 fn main() -> Result<()> {
     SimpleLogger::new()
         .without_timestamps()
@@ -0,0 +1,63 @@
+// binary age v1 header, only recipient stanzas are concerned:
+// age-encryption.org/v1
+// -> tag arg1 arg2
+// body
+// --- mac
+
+const VERSION_LINE: &str = "age-encryption.org/v1";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Stanza<'a> {
+    pub tag: &'a str,
+    pub args: Vec<&'a str>,
+}
+
+pub fn parse_stanzas(buf: &[u8]) -> Option<Vec<Stanza<'_>>> {
+    let end = buf.windows(4).position(|w| w == b"\n---")?;
+    let header = std::str::from_utf8(&buf[..end]).ok()?;
+    let mut lines = header.lines();
+    if lines.next()? != VERSION_LINE {
+        return None;
+    }
+    Some(
+        lines
+            .filter_map(|l| l.strip_prefix("-> "))
+            .filter_map(|l| {
+                let mut fields = l.split(' ');
+                Some(Stanza {
+                    tag: fields.next()?,
+                    args: fields.collect(),
+                })
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_header_stanzas() {
+        let buf = b"age-encryption.org/v1\n-> ssh-ed25519 Fh2PFA abc\nYmFzZTY0\n-> X25519 def\nYmFzZTY0\n--- mac\n\x00\x01payload";
+        let s = parse_stanzas(buf).unwrap();
+        assert_eq!(
+            s,
+            vec![
+                Stanza {
+                    tag: "ssh-ed25519",
+                    args: vec!["Fh2PFA", "abc"]
+                },
+                Stanza {
+                    tag: "X25519",
+                    args: vec!["def"]
+                }
+            ]
+        );
+    }
+    #[test]
+    fn parse_header_invalid() {
+        assert!(parse_stanzas(b"not age\n--- mac\n").is_none());
+        assert!(parse_stanzas(b"age-encryption.org/v1\n-> X25519 def\n").is_none());
+    }
+}
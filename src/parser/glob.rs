@@ -0,0 +1,64 @@
+// shell style wildcard matching, `*` and `?` only
+
+pub fn glob_match(pattern: &str, input: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = input.chars().collect();
+
+    let (mut pi, mut si) = (0, 0);
+    // position of last `*` in pattern, and where it started matching in input
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while si < s.len() {
+        match p.get(pi) {
+            Some('*') => {
+                backtrack = Some((pi, si));
+                pi += 1;
+            }
+            Some(c) if *c == '?' || *c == s[si] => {
+                pi += 1;
+                si += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bs)) => {
+                    pi = bp + 1;
+                    si = bs + 1;
+                    backtrack = Some((bp, bs + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_literal() {
+        assert!(glob_match("tester", "tester"));
+        assert!(!glob_match("tester", "tester2"));
+        assert!(!glob_match("tester2", "tester"));
+    }
+    #[test]
+    fn glob_star() {
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("web-*", "web-01"));
+        assert!(glob_match("*-01", "web-01"));
+        assert!(glob_match("w*b*1", "web-01"));
+        assert!(!glob_match("db-*", "web-01"));
+    }
+    #[test]
+    fn glob_question() {
+        assert!(glob_match("web-0?", "web-01"));
+        assert!(!glob_match("web-?", "web-01"));
+    }
+    #[test]
+    fn glob_backtrack() {
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(!glob_match("*a*b", "xaxxa"));
+    }
+}
@@ -1,64 +1,207 @@
-use age::{Identity, IdentityFile, Recipient};
-use eyre::{eyre, ContextCompat};
+use age::{DecryptError, Identity, IdentityFile, Recipient, secrecy::SecretString};
+use age_core::format::{FileKey, Stanza};
+use eyre::{ContextCompat, eyre};
 use serde::Deserialize;
 
-use super::super::util::callback::UiCallbacks;
+use log::info;
 
+use super::super::util::{
+    agent::{self, AgentIdentity},
+    callback::{UiCallbacks, request_passphrase},
+};
+
+const BINARY_HEADER: &[u8] = b"age-encryption.org/v1";
+const ARMOR_HEADER: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// wrapped by `age -p`, binary or armored
+pub fn is_passphrase_protected(content: &[u8]) -> bool {
+    let trimmed = content.trim_ascii_start();
+    trimmed.starts_with(BINARY_HEADER) || trimmed.starts_with(ARMOR_HEADER)
+}
+
+/// plain identity file content, asking passphrase if it's protected
+fn read_identity_file(path: &str) -> eyre::Result<Vec<u8>> {
+    let content = std::fs::read(path).map_err(|e| eyre!("import from file error: {}", e))?;
+    if !is_passphrase_protected(&content) {
+        return Ok(content);
+    }
+
+    info!("identity {} is passphrase protected", path);
+    let passphrase = request_passphrase(path, &format!("Passphrase of identity {}", path))
+        .with_context(|| eyre!("passphrase required to unlock {}", path))?;
+    unlock_identity(&content, passphrase).map_err(|e| e.wrap_err(eyre!("unlock identity {}", path)))
+}
+
+pub fn unlock_identity(content: &[u8], passphrase: SecretString) -> eyre::Result<Vec<u8>> {
+    use age::{Decryptor, armor::ArmoredReader, scrypt};
+    use std::{io::Read, iter};
+
+    let decryptor = Decryptor::new(ArmoredReader::new(content))
+        .map_err(|e| eyre!("read protected identity error: {}", e))?;
+    if !decryptor.is_scrypt() {
+        return Err(eyre!("age encrypted, but not with passphrase"));
+    }
+
+    let mut plain = vec![];
+    decryptor
+        .decrypt(iter::once(
+            &scrypt::Identity::new(passphrase) as &dyn Identity
+        ))
+        .map_err(|e| eyre!("{}", e))?
+        .read_to_end(&mut plain)?;
+    Ok(plain)
+}
+
+/// wrap identity file content with passphrase, as `age -p -a` does
+pub fn protect_identity(content: &[u8], passphrase: SecretString) -> eyre::Result<Vec<u8>> {
+    use age::{
+        Encryptor,
+        armor::{ArmoredWriter, Format},
+    };
+    use std::io::Write;
+
+    if is_passphrase_protected(content) {
+        return Err(eyre!("identity already passphrase protected"));
+    }
+    // plugin identities parse without their binary present
+    IdentityFile::from_buffer(content).map_err(|e| eyre!("parse identity error: {}", e))?;
+    if !content
+        .split(|b| *b == b'\n')
+        .any(|l| !l.trim_ascii().is_empty() && !l.starts_with(b"#"))
+    {
+        return Err(eyre!("no identity found in file"));
+    }
+
+    let mut out = vec![];
+    let mut writer = Encryptor::with_user_passphrase(passphrase)
+        .wrap_output(ArmoredWriter::wrap_output(&mut out, Format::AsciiArmor)?)?;
+    writer.write_all(content)?;
+    writer.finish().and_then(|armor| armor.finish())?;
+    Ok(out)
+}
+
+/// paths of identity files, tried in order while decrypting
 #[derive(Debug, Deserialize, Clone)]
-pub struct RawIdentity(String);
+pub struct RawIdentity(Vec<String>);
 
 pub struct ParsedIdentity {
     pub identity: Box<dyn Identity>,
-    pub recipient: Box<dyn Recipient + Send>,
+    pub recipients: Vec<Box<dyn Recipient + Send>>,
+}
+
+/// identities from all files, first one could unwrap wins, the same as
+/// passing many identities to age decryptor
+pub struct IdentitySet(Vec<Box<dyn Identity>>);
+
+impl Identity for IdentitySet {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        self.0.iter().find_map(|i| i.unwrap_stanza(stanza))
+    }
+    fn unwrap_stanzas(&self, stanzas: &[Stanza]) -> Option<Result<FileKey, DecryptError>> {
+        self.0.iter().find_map(|i| i.unwrap_stanzas(stanzas))
+    }
 }
 
 impl From<String> for RawIdentity {
     fn from(s: String) -> Self {
+        Self(vec![s])
+    }
+}
+
+impl From<Vec<String>> for RawIdentity {
+    fn from(s: Vec<String>) -> Self {
         Self(s)
     }
 }
 
 impl ParsedIdentity {
-    pub fn from_exist(identity: Box<dyn Identity>, recipient: Box<dyn Recipient + Send>) -> Self {
+    pub fn from_exist(
+        identity: Box<dyn Identity>,
+        recipients: Vec<Box<dyn Recipient + Send>>,
+    ) -> Self {
         Self {
             identity,
-            recipient,
+            recipients,
         }
     }
     pub fn _get_identity(&self) -> &dyn Identity {
         self.identity.as_ref()
     }
-    pub fn _get_recipient(&self) -> &dyn Recipient {
-        self.recipient.as_ref()
+    pub fn _get_recipients(&self) -> impl Iterator<Item = &dyn Recipient> {
+        self.recipients.iter().map(|r| r.as_ref() as &dyn Recipient)
     }
 }
 
 impl TryInto<ParsedIdentity> for RawIdentity {
     type Error = eyre::ErrReport;
     fn try_into(self) -> std::result::Result<ParsedIdentity, Self::Error> {
-        let Self(identity) = self;
-        if identity.is_empty() {
+        let Self(identities) = self;
+        let agent_sock = agent::socket_path();
+        if identities.iter().all(|i| i.is_empty()) && agent_sock.exists() {
+            info!("decrypting through agent {}", agent_sock.display());
+            Ok(ParsedIdentity::from_exist(
+                Box::new(AgentIdentity(agent_sock)),
+                vec![],
+            ))
+        } else if identities.iter().all(|i| i.is_empty()) {
             Err(eyre!(
                 "No identity found, require `vaultix.settings.identity`."
             ))
         } else {
-            macro_rules! create {
-                ($method:ident,  $err_context:expr) => {{
-                    IdentityFile::from_file(identity.clone())
-                        .map_err(|e| eyre!("import from file error: {}", e))?
-                        .with_callbacks(UiCallbacks)
-                        .$method()
-                        .map_err(|e| eyre!("{}", e))?
-                        .into_iter()
-                        .next()
-                        .with_context(|| $err_context)?
-                }};
-            }
-            let ident = create!(into_identities, "into identity fail");
+            let mut idents = Vec::new();
+            let mut recips = Vec::new();
+            for identity in identities.iter().filter(|i| !i.is_empty()) {
+                // unwrap passphrase protected file once, parse twice
+                let content = read_identity_file(identity)?;
+                macro_rules! create {
+                    ($method:ident,  $err_context:expr) => {{
+                        let res = IdentityFile::from_buffer(content.as_slice())
+                            .map_err(|e| eyre!("import from file error: {}", e))?
+                            .with_callbacks(UiCallbacks)
+                            .$method()
+                            .map_err(|e| eyre!("{}", e))?;
+                        Some(res)
+                            .filter(|i| !i.is_empty())
+                            .with_context(|| eyre!("{}: {}", $err_context, identity))?
+                    }};
+                }
+                idents.extend(create!(into_identities, "into identity fail"));
 
-            let recip = create!(to_recipients, "into recip fail");
+                recips.extend(create!(to_recipients, "into recip fail"));
+            }
 
-            Ok(ParsedIdentity::from_exist(ident, recip))
+            Ok(ParsedIdentity::from_exist(
+                Box::new(IdentitySet(idents)),
+                recips,
+            ))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protect_roundtrip() {
+        let key = age::x25519::Identity::generate();
+        let content = format!(
+            "# public key: {}\n{}\n",
+            key.to_public(),
+            age::secrecy::ExposeSecret::expose_secret(&key.to_string())
+        );
+
+        let protected =
+            protect_identity(content.as_bytes(), SecretString::from("some passphrase")).unwrap();
+        assert!(is_passphrase_protected(&protected));
+        assert!(protect_identity(&protected, SecretString::from("again")).is_err());
+
+        let plain = unlock_identity(&protected, SecretString::from("some passphrase")).unwrap();
+        assert_eq!(plain, content.as_bytes());
+        assert!(unlock_identity(&protected, SecretString::from("wrong")).is_err());
+    }
+    #[test]
+    fn protect_not_identity() {
+        assert!(protect_identity(b"hello", SecretString::from("p")).is_err());
+    }
+}
@@ -0,0 +1,84 @@
+// OpenSSH `known_hosts` and `ssh-keyscan` output, they share the format:
+// [@marker] host1,[host2]:port keytype base64 [comment]
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct KnownHost<'a> {
+    pub hosts: Vec<&'a str>,
+    pub key_type: &'a str,
+    pub key: &'a str,
+}
+
+impl KnownHost<'_> {
+    /// pubkey without comment, as `hostPubkey` looks like
+    pub fn pubkey(&self) -> String {
+        format!("{} {}", self.key_type, self.key)
+    }
+
+    /// `id` equals hostname or its first label
+    pub fn matches(&self, id: &str) -> bool {
+        self.hosts
+            .iter()
+            .any(|h| *h == id || h.split('.').next() == Some(id))
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|h| h.split_once("]:"))
+        .map_or(host, |(h, _)| h)
+}
+
+pub fn parse_known_hosts(input: &str) -> Vec<KnownHost<'_>> {
+    input
+        .lines()
+        .map(str::trim)
+        // comment, empty line and `@cert-authority` `@revoked` entries
+        .filter(|l| !l.is_empty() && !l.starts_with('#') && !l.starts_with('@'))
+        .filter_map(|l| {
+            let mut fields = l.split_whitespace();
+            let hosts: Vec<&str> = fields
+                .next()?
+                .split(',')
+                // hashed hostnames are not recoverable
+                .filter(|h| !h.starts_with('|'))
+                .map(strip_port)
+                .collect();
+            let key_type = fields.next()?;
+            let key = fields.next()?;
+            (!hosts.is_empty()).then_some(KnownHost {
+                hosts,
+                key_type,
+                key,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_keyscan_output() {
+        let s = "# tester:22 SSH-2.0-OpenSSH_9.8\ntester ssh-ed25519 AAAAC3Nza\ntester ssh-rsa AAAAB3Nza\n";
+        let l = parse_known_hosts(s);
+        assert_eq!(l.len(), 2);
+        assert_eq!(l[0].hosts, vec!["tester"]);
+        assert_eq!(l[0].pubkey(), "ssh-ed25519 AAAAC3Nza");
+        assert_eq!(l[1].key_type, "ssh-rsa");
+    }
+    #[test]
+    fn parse_known_hosts_multi_host_port() {
+        let s = "tester.example.org,[10.0.0.2]:2222 ssh-ed25519 AAAAC3Nza root@tester";
+        let l = parse_known_hosts(s);
+        assert_eq!(l[0].hosts, vec!["tester.example.org", "10.0.0.2"]);
+        assert!(l[0].matches("tester"));
+        assert!(!l[0].matches("example"));
+        assert_eq!(l[0].pubkey(), "ssh-ed25519 AAAAC3Nza");
+    }
+    #[test]
+    fn parse_known_hosts_skip() {
+        let s = "@cert-authority *.example.org ssh-ed25519 AAAAC3Nza\n|1|c2FsdA==|aGFzaA== ssh-ed25519 AAAAC3Nza\n\ntruncated ssh-ed25519\n";
+        assert!(parse_known_hosts(s).is_empty());
+    }
+}
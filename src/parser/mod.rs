@@ -1,7 +1,11 @@
+pub mod age_header;
+mod glob;
 pub mod identity;
+pub mod known_hosts;
 mod permission;
 pub mod recipient;
 mod template;
 
+pub use glob::glob_match;
 pub use permission::parse_octal_str;
 pub use template::extract_all_hashes;
@@ -2,6 +2,9 @@ use age::{ssh, x25519, Recipient};
 use eyre::eyre;
 use serde::Deserialize;
 
+#[cfg(feature = "plugin")]
+use crate::util::callback::UiCallbacks;
+
 // basically parse host pub key
 
 #[derive(Debug, Deserialize, Clone)]
@@ -13,6 +16,91 @@ impl From<String> for RawRecip {
     }
 }
 
+impl AsRef<str> for RawRecip {
+    fn as_ref(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+/// age `-R` style recipients file, one per line, `#` comments and blank
+/// lines ignored
+pub fn parse_recipients_file(content: &str) -> Vec<RawRecip> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| RawRecip(l.to_string()))
+        .collect()
+}
+
+impl RawRecip {
+    pub fn from_file(path: impl AsRef<std::path::Path>) -> eyre::Result<Vec<Self>> {
+        use eyre::Context;
+        std::fs::read_to_string(path.as_ref())
+            .wrap_err_with(|| eyre!("read recipients file error: {}", path.as_ref().display()))
+            .map(|c| parse_recipients_file(c.as_str()))
+    }
+
+    /// key type and decoded blob of ssh public key
+    fn ssh_blob(&self) -> Option<(&str, Vec<u8>)> {
+        use base64::{Engine, engine::general_purpose::STANDARD};
+
+        match self.0.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [kind, b64, ..] if kind.starts_with("ssh-") => {
+                STANDARD.decode(b64).ok().map(|b| (*kind, b))
+            }
+            _ => None,
+        }
+    }
+
+    /// OpenSSH style `SHA256:...` fingerprint, as `ssh-keygen -l` prints.
+    /// Non-ssh recipients are hashed as their string form.
+    pub fn fingerprint(&self) -> String {
+        use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD};
+        use sha2::{Digest, Sha256};
+
+        let digest = match self.ssh_blob() {
+            Some((_, blob)) => Sha256::digest(blob),
+            None => Sha256::digest(self.0.trim().as_bytes()),
+        };
+        format!("SHA256:{}", STANDARD_NO_PAD.encode(digest))
+    }
+
+    /// stanza tag and key tag that age writes for ssh recipients
+    pub fn ssh_stanza_tag(&self) -> Option<(&str, String)> {
+        use base64::{Engine, engine::general_purpose::STANDARD_NO_PAD};
+        use sha2::{Digest, Sha256};
+
+        self.ssh_blob()
+            .map(|(kind, blob)| (kind, STANDARD_NO_PAD.encode(&Sha256::digest(blob)[..4])))
+    }
+}
+
+/// stanza a recipient leaves in age header
+#[derive(Debug, PartialEq, Eq)]
+pub enum StanzaKind<'a> {
+    /// key type and key tag, naming the exact key
+    Ssh(&'a str, String),
+    /// no arg tells which x25519 key it's for
+    X25519,
+    /// plugins pick their own stanza tag, not always the plugin name
+    Plugin(&'a str),
+}
+
+impl RawRecip {
+    pub fn stanza_kind(&self) -> Option<StanzaKind<'_>> {
+        if let Some((kind, tag)) = self.ssh_stanza_tag() {
+            return Some(StanzaKind::Ssh(kind, tag));
+        }
+        // bech32 `<hrp>1<data>`, data never contains `1`
+        let (hrp, _) = self.0.trim().rsplit_once('1')?;
+        match hrp.strip_prefix("age")? {
+            "" => Some(StanzaKind::X25519),
+            p => p.strip_prefix('1').map(StanzaKind::Plugin),
+        }
+    }
+}
+
 impl TryInto<Box<dyn Recipient + Send>> for RawRecip {
     type Error = eyre::ErrReport;
     fn try_into(self) -> Result<Box<dyn Recipient + Send>, Self::Error> {
@@ -28,6 +116,133 @@ impl TryInto<Box<dyn Recipient + Send>> for RawRecip {
             };
         }
         try_recipients!(recip_str, ssh::Recipient, x25519::Recipient);
+
+        // `age1<plugin>1...`, wrap file key with `age-plugin-<plugin>` in PATH
+        #[cfg(feature = "plugin")]
+        if let Ok(r) = age::plugin::Recipient::from_str(recip_str) {
+            return age::plugin::RecipientPluginV1::new(
+                r.plugin(),
+                std::slice::from_ref(&r),
+                &[],
+                UiCallbacks,
+            )
+            .map(|p| Box::new(p) as Box<dyn Recipient + Send>)
+            .map_err(|e| eyre!("load plugin recipient error: {}", e));
+        }
         Err(eyre!("incompatible recipient type"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_ssh_ignore_comment() {
+        let a = RawRecip::from(String::from(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEOVXgRpY5YK8e5O4W8J0Bu0G8rHIQo3lF4z8d7YKfLn",
+        ));
+        let b = RawRecip::from(String::from(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEOVXgRpY5YK8e5O4W8J0Bu0G8rHIQo3lF4z8d7YKfLn root@tester\n",
+        ));
+        assert_eq!(a.fingerprint(), b.fingerprint());
+        assert!(a.fingerprint().starts_with("SHA256:"));
+    }
+    #[test]
+    fn fingerprint_distinct() {
+        let a = RawRecip::from(String::from(
+            "age1qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqs3290gq",
+        ));
+        let b = RawRecip::from(String::from(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEOVXgRpY5YK8e5O4W8J0Bu0G8rHIQo3lF4z8d7YKfLn",
+        ));
+        assert_ne!(a.fingerprint(), b.fingerprint());
+    }
+    #[test]
+    fn stanza_kinds() {
+        let kind = |s: &str| RawRecip::from(s.to_string()).stanza_kind();
+        assert_eq!(
+            kind("age1qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqs3290gq"),
+            Some(StanzaKind::X25519)
+        );
+        assert_eq!(
+            kind("age1yubikey1qwt50d05nh5vutpdzmlg5wn80xq5negm4uj9ghv0snvdd3yysf5yw3rhl3t"),
+            Some(StanzaKind::Plugin("yubikey"))
+        );
+        assert!(matches!(
+            kind(
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEOVXgRpY5YK8e5O4W8J0Bu0G8rHIQo3lF4z8d7YKfLn"
+            ),
+            Some(StanzaKind::Ssh("ssh-ed25519", _))
+        ));
+        assert_eq!(kind("not a recipient"), None);
+    }
+    #[test]
+    fn recipients_file() {
+        let s = "# backup\nage1qyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqszqgpqyqs3290gq\n\n  ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEOVXgRpY5YK8e5O4W8J0Bu0G8rHIQo3lF4z8d7YKfLn alice  \n";
+        let l = parse_recipients_file(s);
+        assert_eq!(l.len(), 2);
+        assert_eq!(
+            l[1].as_ref(),
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEOVXgRpY5YK8e5O4W8J0Bu0G8rHIQo3lF4z8d7YKfLn alice"
+        );
+        assert!(parse_recipients_file("# nothing\n\n").is_empty());
+    }
+    #[cfg(feature = "plugin")]
+    #[test]
+    fn plugin_recipient_stub() {
+        use crate::{
+            parser::age_header::parse_stanzas,
+            util::secbuf::{Plain, SecBuf},
+        };
+        use std::{fs, os::unix::fs::PermissionsExt, process::Command};
+
+        const STUB_ENV: &str = "VAULTIX_TEST_PLUGIN_STUB";
+
+        // PATH must lead to the stub, set only for a child running this
+        // test alone, never mutated under the parallel runner
+        if std::env::var_os(STUB_ENV).is_none() {
+            // answers recipient-v1 with a fixed stanza, ignoring what it got
+            let stub = r#"#!/bin/sh
+while read -r line; do
+  [ "$line" = "-> done" ] && read -r _ && break
+done
+printf -- '-> recipient-stanza 0 stub vaultix\nc3R1Yg\n'
+read -r _
+read -r _
+printf -- '-> done\n\n'
+"#;
+            let dir = tempfile::tempdir().unwrap();
+            let bin = dir.path().join("age-plugin-stub");
+            fs::write(&bin, stub).unwrap();
+            fs::set_permissions(&bin, fs::Permissions::from_mode(0o755)).unwrap();
+            let path = std::env::var("PATH").unwrap_or_default();
+
+            let status = Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "parser::recipient::tests::plugin_recipient_stub",
+                    "--test-threads=1",
+                ])
+                .env("PATH", format!("{}:{}", dir.path().display(), path))
+                .env(STUB_ENV, "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        let recip: Box<dyn Recipient + Send> =
+            RawRecip::from(String::from("age1stub1wesh2mr5d9uz6ar9wd6qkp73m3"))
+                .try_into()
+                .unwrap();
+        let enc = SecBuf::<Plain>::new(b"hello".to_vec())
+            .encrypt(std::iter::once(recip.as_ref()))
+            .unwrap()
+            .inner();
+        let stanzas = parse_stanzas(&enc).unwrap();
+        assert_eq!(stanzas.len(), 1);
+        assert_eq!(stanzas[0].tag, "stub");
+        assert_eq!(stanzas[0].args, vec!["vaultix"]);
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context, eyre};
+use serde::Deserialize;
+
+use super::{Format, Profile};
+use crate::error::Error;
+
+/**
+Repo side list of secrets a developer decrypts locally
+
+Unlike a host profile, it's written by hand and decrypted with the master
+identity directly, no renc involved.
+*/
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DevProfile {
+    pub secrets: BTreeMap<String, DevSecret>,
+    /// file read from, relative `file` resolved against its dir
+    #[serde(skip)]
+    pub source: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DevSecret {
+    /// age encrypted source in repo
+    pub file: PathBuf,
+    /// variable name, default id in upper case with `-` as `_`
+    #[serde(default)]
+    pub env: String,
+    /// variable holds path of a memory backed file instead of content
+    #[serde(default)]
+    pub path: bool,
+}
+
+impl DevProfile {
+    pub fn from_file(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .wrap_err_with(|| eyre!("read file error: {}", path.display()))
+            .map_err(Error::Profile)?;
+        let value = Profile::read_value(&content, Format::from_path(path))?;
+        let mut profile: Self = serde_json::from_value(value)
+            .wrap_err_with(|| eyre!("parse dev profile fail"))
+            .map_err(Error::Profile)?;
+
+        profile.source = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let dir = profile
+            .source
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        for (id, s) in profile.secrets.iter_mut() {
+            if s.env.is_empty() {
+                s.env = id.to_uppercase().replace('-', "_");
+            }
+            s.file = dir.join(&s.file);
+        }
+        Ok(profile)
+    }
+
+    /// found without decrypting
+    pub fn check(&self) -> Vec<String> {
+        let mut errs = Vec::new();
+        let mut seen = BTreeMap::new();
+        for (id, s) in &self.secrets {
+            // files of `path` secrets are named after id
+            if ["", ".", ".."].contains(&id.as_str()) || id.contains(['/', '\0']) {
+                errs.push(format!("`{}` is not usable as a file name", id));
+            }
+            let valid = s
+                .env
+                .starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && s.env.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if !valid {
+                errs.push(format!("`{}` of {} is not a variable name", s.env, id));
+            }
+            if let Some(other) = seen.insert(s.env.as_str(), id) {
+                errs.push(format!("{} and {} both set `{}`", other, id, s.env));
+            }
+        }
+        errs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dev_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dev.toml");
+        fs::write(
+            &path,
+            r#"
+[secrets.api-token]
+file = "./secrets/staging-api.age"
+
+[secrets.tls-key]
+file = "/abs/tls.age"
+env = "TLS_KEY_FILE"
+path = true
+
+[secrets.dup]
+file = "dup.age"
+env = "API_TOKEN"
+
+[secrets."../escape"]
+file = "escape.age"
+env = "ESCAPE"
+path = true
+"#,
+        )
+        .unwrap();
+        let p = DevProfile::from_file(&path).unwrap();
+
+        let api = &p.secrets["api-token"];
+        assert_eq!(api.env, "API_TOKEN");
+        assert!(!api.path);
+        assert!(api.file.starts_with(p.source.parent().unwrap()));
+        assert_eq!(p.secrets["tls-key"].file, Path::new("/abs/tls.age"));
+        assert_eq!(p.check().len(), 2);
+    }
+}
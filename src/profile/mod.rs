@@ -1,73 +1,464 @@
+pub mod dev;
 pub mod placeholder;
+pub mod schema;
 pub mod template;
 
+use eyre::{Context, eyre};
+use log::info;
 use serde::Deserialize;
-use std::collections::HashMap;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::error::Error;
+use schema::{PROFILE_VERSION, SchemaError};
 
 pub type SecretSet = HashMap<String, Secret>;
 pub type TemplateSet = HashMap<String, Template>;
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct Profile {
+    #[serde(default)]
+    pub version: u64,
     pub settings: Settings,
     pub secrets: SecretSet,
+    #[serde(default)]
     pub templates: TemplateSet,
-    pub before_userborn: Vec<String>,
+    #[serde(default)]
+    pub phases: HashMap<String, Phase>,
+    #[serde(default)]
     pub placeholder: PlaceHolderSet,
+    /// file read from, recorded in deploy manifest
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+/// extracted before users created, linked to `decryptedDirForUser`
+pub const PHASE_EARLY: &str = "early";
+/// the usual stage, linked to `decryptedDir`
+pub const PHASE_DEFAULT: &str = "default";
+
+/// custom deploy stage, built-in ones are not declared
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Phase {
+    /// symlink to generation dir, default `${decryptedDir}-${name}`
+    #[serde(default)]
+    pub link: String,
+}
+
+/// profile serialization, picked by file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    /// json unless `.toml`, `.yaml` or `.yml`
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => Self::Toml,
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Json,
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_as(s, Format::Json)
+    }
+}
+
+impl Profile {
+    /// syntax only, no schema check
+    pub fn read_value(s: &str, format: Format) -> crate::Result<Value> {
+        match format {
+            Format::Json => serde_json::from_str(s).map_err(|e| eyre!(e)),
+            Format::Toml => toml::from_str(s).map_err(|e| eyre!(e)),
+            Format::Yaml => serde_yaml::from_str(s).map_err(|e| eyre!(e)),
+        }
+        .wrap_err_with(|| eyre!("parse profile fail"))
+        .map_err(Error::Profile)
+    }
+
+    /// migrate to current version and list every schema violation
+    pub fn check_value(value: &mut Value) -> Vec<SchemaError> {
+        match schema::migrate(value) {
+            Ok(from) if from < PROFILE_VERSION => {
+                info!("profile migrated from v{} to v{}", from, PROFILE_VERSION);
+                schema::validate(value)
+            }
+            Ok(_) => schema::validate(value),
+            Err(e) => vec![e],
+        }
+    }
+
+    pub fn parse_as(s: &str, format: Format) -> crate::Result<Self> {
+        let mut value = Self::read_value(s, format)?;
+        let errs = Self::check_value(&mut value);
+        if !errs.is_empty() {
+            return Err(Error::Schema(errs));
+        }
+        serde_json::from_value(value)
+            .map(Self::fill_defaults)
+            .wrap_err_with(|| eyre!("parse profile fail"))
+            .map_err(Error::Profile)
+    }
+
+    /// profile generated by nixos module, or written by hand
+    pub fn from_file(path: impl AsRef<Path>) -> crate::Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .wrap_err_with(|| eyre!("read file error: {}", path.display()))
+            .map_err(Error::Profile)?;
+        Self::parse_as(content.as_str(), Format::from_path(path)).map(|p| Self {
+            source: Some(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())),
+            ..p
+        })
+    }
+
+    /// same defaults as nixos module gives, for hand written profiles
+    fn fill_defaults(mut self) -> Self {
+        for (k, p) in self.phases.iter_mut() {
+            if p.link.is_empty() {
+                p.link = format!("{}-{}", self.settings.decrypted_dir, k);
+            }
+        }
+        for (k, t) in self.templates.iter_mut() {
+            if t.name.is_empty() {
+                t.name = k.clone();
+            }
+        }
+        for (k, s) in self.secrets.iter_mut() {
+            // as nixos module derives, hand written profiles can't know it
+            if !self.templates.is_empty() && !self.placeholder.0.contains_key(k) {
+                let hash = hex::encode(Sha256::digest(k.as_bytes()));
+                self.placeholder
+                    .0
+                    .insert(k.clone(), format!("{{{{ {} }}}}", hash));
+            }
+            if s.id.is_empty() {
+                s.id = k.clone();
+            }
+            if s.name.is_empty() {
+                s.name = k.clone();
+            }
+            if let Some(d) = s.keyring.as_mut().filter(|d| d.description.is_empty()) {
+                d.description = format!("vaultix:{}", s.id);
+            }
+        }
+        // default path follows where the phase links to
+        let links: HashMap<String, String> = [PHASE_EARLY, PHASE_DEFAULT]
+            .into_iter()
+            .chain(self.phases.keys().map(String::as_str))
+            .filter_map(|p| Some((p.to_string(), self.phase_link(p)?.to_string())))
+            .collect();
+        let default_path = |phase: &str, name: &str| {
+            links
+                .get(phase)
+                .map(|l| Path::new(l).join(name).to_string_lossy().to_string())
+                .unwrap_or_default()
+        };
+        for s in self.secrets.values_mut().filter(|s| s.path.is_empty()) {
+            s.path = default_path(s.phase.as_str(), s.name.as_str());
+        }
+        for t in self.templates.values_mut().filter(|t| t.path.is_empty()) {
+            t.path = default_path(t.phase.as_str(), t.name.as_str());
+        }
+        self
+    }
+
+    /// where the generation dir of phase is linked to
+    pub fn phase_link(&self, phase: &str) -> Option<&str> {
+        match phase {
+            PHASE_EARLY => Some(self.settings.decrypted_dir_for_user.as_str()),
+            PHASE_DEFAULT => Some(self.settings.decrypted_dir.as_str()),
+            _ => self.phases.get(phase).map(|p| p.link.as_str()),
+        }
+    }
+
+    /// use `<dir>/<hostIdentifier>` as cache instead of `cacheInStore`
+    pub fn with_cache_dir(&mut self, dir: impl AsRef<Path>) {
+        self.settings.cache_in_store = dir
+            .as_ref()
+            .join(self.settings.host_identifier.as_str())
+            .to_string_lossy()
+            .to_string();
+    }
+
+    /// put every destination, mount point and phase link under `root`
+    pub fn with_root(&mut self, root: impl AsRef<Path>) {
+        let under = |p: &mut String| {
+            if !p.is_empty() {
+                *p = root
+                    .as_ref()
+                    .join(p.trim_start_matches('/'))
+                    .to_string_lossy()
+                    .to_string();
+            }
+        };
+        under(&mut self.settings.decrypted_dir);
+        under(&mut self.settings.decrypted_dir_for_user);
+        under(&mut self.settings.decrypted_mount_point);
+        self.phases.values_mut().for_each(|p| under(&mut p.link));
+        self.secrets.values_mut().for_each(|s| under(&mut s.path));
+        self.templates.values_mut().for_each(|t| under(&mut t.path));
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Clone, Default)]
 pub struct PlaceHolderSet(pub HashMap<String, String>);
 
+fn default_mode() -> String {
+    "0400".into()
+}
+
+fn default_owner() -> String {
+    "root".into()
+}
+
+fn default_trim() -> bool {
+    true
+}
+
+fn default_phase() -> String {
+    PHASE_DEFAULT.into()
+}
+
 #[derive(Debug, Deserialize, Clone, Hash, Eq, PartialEq)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct Secret {
+    #[serde(default)]
     pub id: String,
     pub file: String,
+    #[serde(default = "default_owner")]
     pub group: String,
+    #[serde(default = "default_mode")]
     pub mode: String,
+    #[serde(default)]
     pub name: String,
+    #[serde(default = "default_owner")]
     pub owner: String,
+    #[serde(default)]
     pub path: String,
+    #[serde(default = "default_phase")]
+    pub phase: String,
+    /// extra POSIX ACL entries, `user:nginx:r`
+    #[serde(default)]
+    pub acl: Vec<String>,
+    /// add to kernel keyring instead of writing a file
+    #[serde(default)]
+    pub keyring: Option<KeyringDest>,
+    /// only held by `vaultix serve` instead of writing a file
+    #[serde(default)]
+    pub serve: Option<ServePolicy>,
+}
+
+impl Secret {
+    /// written to filesystem or keyring by deploy, not only served
+    pub fn deployed(&self) -> bool {
+        self.serve.is_none()
+    }
 }
 
+/// peers `vaultix serve` hands a secret to, besides its owner and group
 #[derive(Debug, Deserialize, Clone, Hash, Eq, PartialEq, Default)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ServePolicy {
+    /// user names or uids
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// group names or gids, matched with primary and supplementary groups of peer
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// peer must be running one of these, any if empty
+    #[serde(default)]
+    pub executables: Vec<String>,
+}
+
+/// where in kernel keyring a secret is added
+#[derive(Debug, Deserialize, Clone, Hash, Eq, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct KeyringDest {
+    #[serde(default)]
+    pub r#type: KeyType,
+    #[serde(default)]
+    pub keyring: KeyringKind,
+    /// keyring of this name nested in `keyring`, created if missing
+    #[serde(default)]
+    pub name: Option<String>,
+    /// key description, default `vaultix:<id>`
+    #[serde(default)]
+    pub description: String,
+    /// permission mask in hex, as `keyctl setperm` takes
+    #[serde(default = "default_key_perm")]
+    pub perm: String,
+    /// seconds until the key expires, 0 for never
+    #[serde(default)]
+    pub timeout: u32,
+}
+
+/// `user` keys could be read back, `logon` keys only by kernel
+#[derive(Debug, Deserialize, Clone, Copy, Hash, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    #[default]
+    User,
+    Logon,
+}
+
+/// `user` keyring of root deploying it, or `persistent` keyring of owner;
+/// session keyring of deploy dies with it
+#[derive(Debug, Deserialize, Clone, Copy, Hash, Eq, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyringKind {
+    #[default]
+    User,
+    Persistent,
+}
+
+#[derive(Debug, Deserialize, Clone, Hash, Eq, PartialEq, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct Template {
+    #[serde(default)]
     pub name: String,
+    #[serde(default)]
     pub content: String,
+    #[serde(default = "default_trim")]
     pub trim: bool,
+    #[serde(default = "default_owner")]
     pub group: String,
+    #[serde(default = "default_mode")]
     pub mode: String,
+    #[serde(default = "default_owner")]
     pub owner: String,
+    #[serde(default)]
     pub path: String,
+    #[serde(default = "default_phase")]
+    pub phase: String,
+    /// extra POSIX ACL entries, `user:nginx:r`
+    #[serde(default)]
+    pub acl: Vec<String>,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
 pub struct Settings {
+    #[serde(default = "default_decrypted_dir")]
     pub decrypted_dir: String,
+    #[serde(default = "default_decrypted_dir_for_user")]
     pub decrypted_dir_for_user: String,
+    #[serde(default = "default_decrypted_mount_point")]
     pub decrypted_mount_point: String,
     pub host_identifier: String,
     pub host_pubkey: String,
+    #[serde(default = "default_host_keys")]
     pub host_keys: Vec<HostKey>,
+    /// host cache dir, any path in standalone mode
+    #[serde(default, alias = "cacheDir")]
     pub cache_in_store: String,
+    #[serde(default)]
+    pub extra_recipients: Vec<String>,
+    /// unresolved owner fails the deploy instead of deferring or skipping
+    #[serde(default)]
+    pub strict_owner: bool,
+    /// filesystem mounted on decrypted mount point
+    #[serde(default)]
+    pub secrets_fs: SecretsFs,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SecretsFs {
+    #[serde(default)]
+    pub r#type: FsType,
+    /// tmpfs `size=`, `16M` or `5%`
+    #[serde(default)]
+    pub size: Option<String>,
+    /// tmpfs pages never swapped out, linux 6.4+
+    #[serde(default = "default_noswap")]
+    pub noswap: bool,
+    #[serde(default = "default_fs_flags")]
+    pub flags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FsType {
+    #[default]
+    Ramfs,
+    Tmpfs,
 }
 
-#[derive(Debug, Deserialize)]
+impl Default for SecretsFs {
+    fn default() -> Self {
+        Self {
+            r#type: FsType::default(),
+            size: None,
+            noswap: default_noswap(),
+            flags: default_fs_flags(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
 pub struct HostKey {
     pub path: String,
     pub r#type: String,
 }
 
+fn default_decrypted_dir() -> String {
+    "/run/vaultix".into()
+}
+
+fn default_decrypted_dir_for_user() -> String {
+    "/run/vaultix-for-user".into()
+}
+
+fn default_decrypted_mount_point() -> String {
+    "/run/vaultix.d".into()
+}
+
+/// possessor all, owner view, read and search
+fn default_key_perm() -> String {
+    "3f0b0000".into()
+}
+
+fn default_noswap() -> bool {
+    true
+}
+
+fn default_fs_flags() -> Vec<String> {
+    ["nosuid", "nodev", "noexec", "relatime"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_host_keys() -> Vec<HostKey> {
+    vec![HostKey {
+        path: "/etc/ssh/ssh_host_ed25519_key".into(),
+        r#type: "ed25519".into(),
+    }]
+}
+
 pub trait DeployFactor {
     fn mode(&self) -> &String;
     fn owner(&self) -> &String;
     fn name(&self) -> &String;
     fn group(&self) -> &String;
     fn path(&self) -> &String;
+    fn acl(&self) -> &[String];
 }
 
 macro_rules! impl_deploy_factor {
@@ -78,6 +469,9 @@ macro_rules! impl_deploy_factor {
                     &self.$field
                 }
             )+
+            fn acl(&self) -> &[String] {
+                &self.acl
+            }
         }
     };
 }
@@ -85,3 +479,61 @@ macro_rules! impl_deploy_factor {
 impl_deploy_factor!(&Secret, [mode, owner, name, group, path]);
 
 impl_deploy_factor!(&Template, [mode, owner, name, group, path]);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hand_written_toml() {
+        let p = Profile::parse_as(
+            r#"
+[settings]
+hostIdentifier = "web"
+hostPubkey = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEm1Wf+1JsqkIrWJ3TrSPIuaxTxh5qlqfW6w3TfBUnMg"
+cacheDir = "/var/lib/vaultix/cache/web"
+
+[secrets.db]
+file = "./secrets/db.age"
+owner = "postgres"
+"#,
+            Format::Toml,
+        )
+        .unwrap();
+        let s = p.secrets.get("db").unwrap();
+        assert_eq!(s.id, "db");
+        assert_eq!(s.path, "/run/vaultix/db");
+        assert_eq!((s.owner.as_str(), s.group.as_str()), ("postgres", "root"));
+        assert_eq!(s.mode, "0400");
+        assert_eq!(p.settings.cache_in_store, "/var/lib/vaultix/cache/web");
+        assert_eq!(p.settings.host_keys[0].r#type, "ed25519");
+    }
+
+    #[test]
+    fn hand_written_yaml() {
+        let p = Profile::parse_as(
+            "settings:\n  hostIdentifier: web\n  hostPubkey: key\nsecrets:\n  token:\n    file: ./token.age\n    name: api\n",
+            Format::Yaml,
+        )
+        .unwrap();
+        assert_eq!(p.secrets["token"].path, "/run/vaultix/api");
+        assert_eq!(Format::from_path(Path::new("a.yml")), Format::Yaml);
+    }
+
+    #[test]
+    fn rooted() {
+        let mut p = Profile::parse_as(
+            "settings:\n  hostIdentifier: web\n  hostPubkey: key\nsecrets:\n  a:\n    file: ./a.age\n  b:\n    file: ./b.age\n    path: /etc/b\n",
+            Format::Yaml,
+        )
+        .unwrap();
+        p.with_root("/tmp/img");
+        assert_eq!(p.secrets["a"].path, "/tmp/img/run/vaultix/a");
+        assert_eq!(p.secrets["b"].path, "/tmp/img/etc/b");
+        assert_eq!(p.decrypted_mount_point(), "/tmp/img/run/vaultix.d");
+        assert_eq!(
+            p.phase_link(PHASE_EARLY),
+            Some("/tmp/img/run/vaultix-for-user")
+        );
+    }
+}
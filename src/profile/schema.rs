@@ -0,0 +1,345 @@
+use std::{collections::HashMap, fmt};
+
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use serde_path_to_error::Segment;
+
+use super::{PHASE_DEFAULT, PHASE_EARLY, Phase, Secret, Settings, Template};
+use crate::{parser::parse_octal_str, util::acl::parse_spec};
+
+/// version written by nixos module, unversioned profiles are 0
+pub const PROFILE_VERSION: u64 = 2;
+
+const TOP_LEVEL_KEYS: [&str; 6] = [
+    "version",
+    "settings",
+    "secrets",
+    "templates",
+    "phases",
+    "placeholder",
+];
+
+/// one problem found in profile, located by JSON path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaError {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+fn push_key(path: &mut String, key: &str) {
+    if !key.is_empty()
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !key.starts_with(|c: char| c.is_ascii_digit())
+    {
+        path.push('.');
+        path.push_str(key);
+    } else {
+        path.push_str(format!("[{:?}]", key).as_str());
+    }
+}
+
+fn join(base: &str, key: &str) -> String {
+    let mut p = base.to_string();
+    push_key(&mut p, key);
+    p
+}
+
+type Migration = fn(&mut Map<String, Value>);
+
+/// index n upgrades version n to n + 1
+const MIGRATIONS: [Migration; PROFILE_VERSION as usize] = [v0_to_v1, v1_to_v2];
+
+/// v0 was the whole `config.vaultix` dumped, with the package and extra
+/// attributes of openssh host keys
+fn v0_to_v1(p: &mut Map<String, Value>) {
+    p.remove("package");
+    if let Some(Value::Array(keys)) = p.get_mut("settings").and_then(|s| s.get_mut("hostKeys")) {
+        keys.iter_mut()
+            .filter_map(Value::as_object_mut)
+            .for_each(|k| k.retain(|k, _| k == "path" || k == "type"));
+    }
+}
+
+/// `beforeUserborn` list became `phase` of each item
+fn v1_to_v2(p: &mut Map<String, Value>) {
+    let Some(Value::Array(before)) = p.remove("beforeUserborn") else {
+        return;
+    };
+    for id in before.iter().filter_map(Value::as_str) {
+        ["secrets", "templates"]
+            .iter()
+            .filter_map(|k| p.get_mut(*k)?.get_mut(id)?.as_object_mut())
+            .for_each(|i| {
+                i.insert("phase".into(), PHASE_EARLY.into());
+            });
+    }
+}
+
+/// upgrade in place, returns the version it was
+pub fn migrate(value: &mut Value) -> Result<u64, SchemaError> {
+    let Some(p) = value.as_object_mut() else {
+        return Err(SchemaError {
+            path: "$".into(),
+            message: "profile must be an object".into(),
+        });
+    };
+    let from = match p.get("version") {
+        None => 0,
+        Some(v) => v.as_u64().ok_or_else(|| SchemaError {
+            path: "$.version".into(),
+            message: format!("expect unsigned integer, found {}", v),
+        })?,
+    };
+    if from > PROFILE_VERSION {
+        return Err(SchemaError {
+            path: "$.version".into(),
+            message: format!(
+                "version {} is newer than supported {}, upgrade vaultix",
+                from, PROFILE_VERSION
+            ),
+        });
+    }
+    MIGRATIONS[from as usize..].iter().for_each(|m| m(p));
+    p.insert("version".into(), PROFILE_VERSION.into());
+    Ok(from)
+}
+
+fn check<T: DeserializeOwned>(value: &Value, base: &str, errs: &mut Vec<SchemaError>) -> Option<T> {
+    serde_path_to_error::deserialize::<_, T>(value)
+        .map_err(|e| {
+            let mut path = base.to_string();
+            e.path().iter().for_each(|s| match s {
+                Segment::Seq { index } => path.push_str(format!("[{}]", index).as_str()),
+                Segment::Map { key } => push_key(&mut path, key),
+                Segment::Enum { variant } => push_key(&mut path, variant),
+                Segment::Unknown => path.push_str(".?"),
+            });
+            errs.push(SchemaError {
+                path,
+                message: e.into_inner().to_string(),
+            });
+        })
+        .ok()
+}
+
+fn check_mode(mode: &str, path: String, errs: &mut Vec<SchemaError>) {
+    if parse_octal_str(mode).is_err() {
+        errs.push(SchemaError {
+            path,
+            message: format!("invalid octal mode `{}`", mode),
+        });
+    }
+}
+
+fn check_acl(acl: &[String], path: &str, errs: &mut Vec<SchemaError>) {
+    acl.iter().enumerate().for_each(|(i, a)| {
+        if let Err(e) = parse_spec(a) {
+            errs.push(SchemaError {
+                path: format!("{}[{}]", join(path, "acl"), i),
+                message: e.to_string(),
+            });
+        }
+    });
+}
+
+/**
+Collect every problem of a migrated profile
+
+Each secret and template is checked on its own, so one malformed entry
+doesn't hide the others.
+*/
+pub fn validate(value: &Value) -> Vec<SchemaError> {
+    let mut errs = Vec::new();
+    let Some(p) = value.as_object() else {
+        return vec![SchemaError {
+            path: "$".into(),
+            message: "profile must be an object".into(),
+        }];
+    };
+
+    p.keys()
+        .filter(|k| !TOP_LEVEL_KEYS.contains(&k.as_str()))
+        .for_each(|k| {
+            errs.push(SchemaError {
+                path: join("$", k),
+                message: format!("unknown field `{}`", k),
+            })
+        });
+
+    match p.get("settings") {
+        Some(s) => {
+            check::<Settings>(s, "$.settings", &mut errs)
+                .iter()
+                .flat_map(|s| s.secrets_fs.check())
+                .for_each(|message| {
+                    errs.push(SchemaError {
+                        path: "$.settings.secretsFs".into(),
+                        message,
+                    })
+                });
+        }
+        None => errs.push(SchemaError {
+            path: "$".into(),
+            message: "missing field `settings`".into(),
+        }),
+    }
+
+    let phases = p
+        .get("phases")
+        .and_then(|v| check::<HashMap<String, Phase>>(v, "$.phases", &mut errs))
+        .unwrap_or_default();
+    phases
+        .keys()
+        .filter(|k| [PHASE_EARLY, PHASE_DEFAULT].contains(&k.as_str()))
+        .for_each(|k| {
+            errs.push(SchemaError {
+                path: join("$.phases", k),
+                message: format!("`{}` is built-in, not declarable", k),
+            })
+        });
+    let check_phase = |phase: &str, path: &str, errs: &mut Vec<SchemaError>| {
+        if ![PHASE_EARLY, PHASE_DEFAULT].contains(&phase) && !phases.contains_key(phase) {
+            errs.push(SchemaError {
+                path: join(path, "phase"),
+                message: format!("phase `{}` not declared in `phases`", phase),
+            });
+        }
+    };
+
+    match p.get("secrets") {
+        Some(Value::Object(secrets)) => secrets.iter().for_each(|(k, v)| {
+            let path = join("$.secrets", k);
+            if let Some(s) = check::<Secret>(v, path.as_str(), &mut errs) {
+                check_mode(s.mode.as_str(), join(path.as_str(), "mode"), &mut errs);
+                check_phase(s.phase.as_str(), path.as_str(), &mut errs);
+                check_acl(&s.acl, path.as_str(), &mut errs);
+                if s.keyring.is_some() && s.serve.is_some() {
+                    errs.push(SchemaError {
+                        path: join(path.as_str(), "serve"),
+                        message: "served secrets are never added to keyring".into(),
+                    });
+                }
+                if let Some(k) = &s.keyring {
+                    let path = join(path.as_str(), "keyring");
+                    let mut problems = k.check();
+                    if !s.acl.is_empty() {
+                        problems.push("`acl` doesn't apply to kernel keys, use `perm`".into());
+                    }
+                    // a key can't be chowned later like deferred files
+                    let early = |n: &str| n == "root" || n.parse::<u32>().is_ok();
+                    if s.phase == PHASE_EARLY && !(early(&s.owner) && early(&s.group)) {
+                        problems.push(
+                            "keys of early phase must be owned by `root` or numeric ids".into(),
+                        );
+                    }
+                    problems.into_iter().for_each(|message| {
+                        errs.push(SchemaError {
+                            path: path.clone(),
+                            message,
+                        })
+                    });
+                }
+            }
+        }),
+        Some(v) => errs.push(SchemaError {
+            path: "$.secrets".into(),
+            message: format!("expect object, found {}", v),
+        }),
+        None => errs.push(SchemaError {
+            path: "$".into(),
+            message: "missing field `secrets`".into(),
+        }),
+    }
+
+    match p.get("templates") {
+        Some(Value::Object(templates)) => templates.iter().for_each(|(k, v)| {
+            let path = join("$.templates", k);
+            if let Some(t) = check::<Template>(v, path.as_str(), &mut errs) {
+                check_mode(t.mode.as_str(), join(path.as_str(), "mode"), &mut errs);
+                check_phase(t.phase.as_str(), path.as_str(), &mut errs);
+                check_acl(&t.acl, path.as_str(), &mut errs);
+            }
+        }),
+        Some(v) => errs.push(SchemaError {
+            path: "$.templates".into(),
+            message: format!("expect object, found {}", v),
+        }),
+        None => {}
+    }
+
+    if let Some(v) = p.get("placeholder") {
+        check::<super::PlaceHolderSet>(v, "$.placeholder", &mut errs);
+    }
+
+    errs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn migrate_v0() {
+        let mut v = json!({
+            "package": "/nix/store/xxx-vaultix",
+            "settings": {
+                "hostKeys": [{ "path": "/etc/ssh/ssh_host_rsa_key", "type": "rsa", "bits": 4096 }]
+            },
+            "secrets": {}
+        });
+        assert_eq!(migrate(&mut v), Ok(0));
+        assert_eq!(v["version"], json!(PROFILE_VERSION));
+        assert!(v.get("package").is_none());
+        assert!(v["settings"]["hostKeys"][0].get("bits").is_none());
+
+        let mut v = json!({
+            "version": 1,
+            "secrets": { "a": {}, "b": {} },
+            "beforeUserborn": ["a"]
+        });
+        assert_eq!(migrate(&mut v), Ok(1));
+        assert_eq!(v["secrets"]["a"]["phase"], json!(PHASE_EARLY));
+        assert!(v["secrets"]["b"].get("phase").is_none());
+        assert!(v.get("beforeUserborn").is_none());
+
+        let mut v = json!({ "version": 99 });
+        assert_eq!(migrate(&mut v).unwrap_err().path, "$.version");
+    }
+
+    #[test]
+    fn validate_collects_all() {
+        let v = json!({
+            "version": 2,
+            "typo": true,
+            "settings": { "hostIdentifier": "web", "hostPubkey": "key" },
+            "secrets": {
+                "a": { "file": "./a.age", "mod": "0400" },
+                "b-c": { "file": 1 },
+                "ok": { "file": "./ok.age", "mode": "999" },
+                "home": { "file": "./home.age", "phase": "home" },
+                "shared": { "file": "./shared.age", "acl": ["user:nginx:r", "nginx:r"] },
+                "luks": { "file": "./luks.age", "keyring": { "type": "logon", "description": "root" } },
+                "boot": { "file": "./boot.age", "phase": "early", "owner": "app", "keyring": {} }
+            },
+            "phases": { "early": {} }
+        });
+        let errs: Vec<String> = validate(&v).iter().map(|e| e.path.clone()).collect();
+        assert!(errs.contains(&"$.typo".to_string()));
+        assert!(errs.iter().any(|e| e.starts_with("$.secrets.a")));
+        assert!(errs.contains(&"$.secrets[\"b-c\"].file".to_string()));
+        assert!(errs.contains(&"$.secrets.ok.mode".to_string()));
+        assert!(errs.contains(&"$.secrets.home.phase".to_string()));
+        assert!(errs.contains(&"$.phases.early".to_string()));
+        assert!(errs.contains(&"$.secrets.shared.acl[1]".to_string()));
+        assert!(errs.contains(&"$.secrets.luks.keyring".to_string()));
+        assert!(errs.contains(&"$.secrets.boot.keyring".to_string()));
+        assert_eq!(errs.len(), 9);
+    }
+}
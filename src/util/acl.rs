@@ -0,0 +1,182 @@
+use std::{ffi::CStr, fs::File, io, os::fd::AsRawFd};
+
+use eyre::{Context, Result, bail, eyre};
+
+use super::set_owner_group::IdMap;
+
+const XATTR_ACL_ACCESS: &CStr = c"system.posix_acl_access";
+const ACL_EA_VERSION: u32 = 2;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// ordered as kernel requires, by tag then id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Entry {
+    tag: u16,
+    id: u32,
+    perm: u16,
+}
+
+/// user or group, name or numeric id, and permission bits
+#[derive(Debug, PartialEq, Eq)]
+pub struct Spec<'a> {
+    pub is_user: bool,
+    pub who: &'a str,
+    pub perm: u16,
+}
+
+/// `user:nginx:r`, `u:1000:rw-` or `group:web:4`
+pub fn parse_spec(s: &str) -> Result<Spec<'_>> {
+    let [kind, who, perm] = s.splitn(3, ':').collect::<Vec<_>>()[..] else {
+        bail!("acl entry `{}` not in form of `user:<name>:<perm>`", s);
+    };
+    let is_user = match kind {
+        "u" | "user" => true,
+        "g" | "group" => false,
+        _ => bail!("acl entry `{}` must be of `user` or `group`", s),
+    };
+    if who.is_empty() {
+        bail!("acl entry `{}` names nobody", s);
+    }
+    let perm = match perm.parse::<u16>() {
+        Ok(n) if n <= 7 => n,
+        _ if !perm.is_empty() && perm.len() <= 3 => perm.chars().try_fold(0, |acc, c| match c {
+            'r' => Ok(acc | 4),
+            'w' => Ok(acc | 2),
+            'x' => Ok(acc | 1),
+            '-' => Ok(acc),
+            _ => Err(eyre!("invalid permission `{}` in acl entry `{}`", perm, s)),
+        })?,
+        _ => bail!("invalid permission `{}` in acl entry `{}`", perm, s),
+    };
+    Ok(Spec { is_user, who, perm })
+}
+
+/// named entries, users and groups looked up now
+pub fn resolve(acl: &[String], ids: &IdMap) -> Result<Vec<Entry>> {
+    acl.iter()
+        .map(|s| {
+            let Spec { is_user, who, perm } = parse_spec(s)?;
+            let (tag, id) = if is_user {
+                (ACL_USER, ids.uid(who)?)
+            } else {
+                (ACL_GROUP, ids.gid(who)?)
+            };
+            Ok(Entry { tag, id, perm })
+        })
+        .collect()
+}
+
+/// xattr value of named entries together with those derived from mode
+fn encode(mode: u32, named: &[Entry]) -> Vec<u8> {
+    let bits = |shift: u32| ((mode >> shift) & 0o7) as u16;
+    let obj = |tag, perm| Entry {
+        tag,
+        id: ACL_UNDEFINED_ID,
+        perm,
+    };
+    let mut all = named.to_vec();
+    all.extend([
+        obj(ACL_USER_OBJ, bits(6)),
+        obj(ACL_GROUP_OBJ, bits(3)),
+        obj(ACL_OTHER, bits(0)),
+    ]);
+    if !named.is_empty() {
+        let mask = named.iter().fold(bits(3), |m, e| m | e.perm);
+        all.push(obj(ACL_MASK, mask));
+    }
+    all.sort();
+    all.dedup_by_key(|e| (e.tag, e.id));
+
+    let mut buf = ACL_EA_VERSION.to_le_bytes().to_vec();
+    all.iter().for_each(|e| {
+        buf.extend(e.tag.to_le_bytes());
+        buf.extend(e.perm.to_le_bytes());
+        buf.extend(e.id.to_le_bytes());
+    });
+    buf
+}
+
+/// set access ACL of file, mode already applied
+pub fn set_acl(file: &File, named: &[Entry]) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = file.metadata()?.permissions().mode();
+    let buf = encode(mode, named);
+    let res = unsafe {
+        libc::fsetxattr(
+            file.as_raw_fd(),
+            XATTR_ACL_ACCESS.as_ptr(),
+            buf.as_ptr().cast(),
+            buf.len(),
+            0,
+        )
+    };
+    if res == -1 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EOPNOTSUPP) {
+            return Err(e).wrap_err("secrets filesystem has no POSIX ACL support");
+        }
+        return Err(e).wrap_err("set acl failed");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_forms() {
+        let s = parse_spec("user:nginx:r").unwrap();
+        assert_eq!((s.is_user, s.who, s.perm), (true, "nginx", 4));
+        assert_eq!(parse_spec("g:1001:rw-").unwrap().perm, 6);
+        assert_eq!(parse_spec("u:a:5").unwrap().perm, 5);
+        for bad in ["nginx:r", "other:a:r", "u::r", "u:a:rwxr", "u:a:8", "u:a:"] {
+            assert!(parse_spec(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn encode_sorted_with_mask() {
+        let named = [
+            Entry {
+                tag: ACL_GROUP,
+                id: 7,
+                perm: 4,
+            },
+            Entry {
+                tag: ACL_USER,
+                id: 1000,
+                perm: 6,
+            },
+        ];
+        let buf = encode(0o640, &named);
+        let tags: Vec<u16> = buf[4..]
+            .chunks(8)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        assert_eq!(
+            tags,
+            [
+                ACL_USER_OBJ,
+                ACL_USER,
+                ACL_GROUP_OBJ,
+                ACL_GROUP,
+                ACL_MASK,
+                ACL_OTHER
+            ]
+        );
+        // mask covers group class and every named entry
+        assert_eq!(
+            u16::from_le_bytes([buf[4 + 4 * 8 + 2], buf[4 + 4 * 8 + 3]]),
+            6
+        );
+        assert_eq!(encode(0o600, &[]).len(), 4 + 3 * 8);
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    mem,
+    net::Shutdown,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use age::{DecryptError, Identity, secrecy::ExposeSecret};
+use age_core::format::{FileKey, Stanza};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+
+pub const SOCKET_ENV: &str = "VAULTIX_AGENT_SOCK";
+
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `$VAULTIX_AGENT_SOCK`, or under `$XDG_RUNTIME_DIR`, or per-uid dir in /tmp
+pub fn socket_path() -> PathBuf {
+    if let Ok(p) = std::env::var(SOCKET_ENV) {
+        return p.into();
+    }
+    let mut p = std::env::var("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(format!("/tmp/vaultix-{}", unsafe { libc::getuid() })));
+    p.push("vaultix");
+    p.push("agent.sock");
+    p
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WireStanza {
+    pub tag: String,
+    pub args: Vec<String>,
+    pub body: String,
+}
+
+impl From<&Stanza> for WireStanza {
+    fn from(value: &Stanza) -> Self {
+        Self {
+            tag: value.tag.clone(),
+            args: value.args.clone(),
+            body: STANDARD.encode(&value.body),
+        }
+    }
+}
+
+impl TryFrom<WireStanza> for Stanza {
+    type Error = base64::DecodeError;
+    fn try_from(value: WireStanza) -> Result<Self, Self::Error> {
+        Ok(Stanza {
+            tag: value.tag,
+            args: value.args,
+            body: STANDARD.decode(value.body)?,
+        })
+    }
+}
+
+/// header stanzas go to agent, only the file key comes back
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Unwrap(Vec<WireStanza>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    FileKey(String),
+    NoMatch,
+    Error(String),
+}
+
+impl Response {
+    pub fn from_unwrapped(res: Option<Result<FileKey, DecryptError>>) -> Self {
+        match res {
+            Some(Ok(k)) => Self::FileKey(STANDARD.encode(k.expose_secret())),
+            Some(Err(e)) => Self::Error(e.to_string()),
+            None => Self::NoMatch,
+        }
+    }
+}
+
+pub fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    peer_cred(stream).map(|c| c.uid)
+}
+
+/// pid, uid and gid of peer when it connected
+pub fn peer_cred(stream: &UnixStream) -> io::Result<libc::ucred> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred)
+}
+
+/// not in libc yet, linux 6.5+
+const SO_PEERPIDFD: libc::c_int = 77;
+
+/// supplementary groups of peer when it connected, without the primary one
+pub fn peer_groups(stream: &UnixStream) -> io::Result<Vec<u32>> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut len = mem::size_of_val(groups.as_slice()) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERGROUPS,
+                groups.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        let n = len as usize / mem::size_of::<libc::gid_t>();
+        match ret {
+            -1 if io::Error::last_os_error().raw_os_error() == Some(libc::ERANGE) => {
+                groups.resize(n, 0)
+            }
+            -1 => return Err(io::Error::last_os_error()),
+            _ => {
+                groups.truncate(n);
+                return Ok(groups);
+            }
+        }
+    }
+}
+
+/// pidfd of peer, stays on that process even once its pid is reused
+pub fn peer_pidfd(stream: &UnixStream) -> io::Result<OwnedFd> {
+    let mut fd: libc::c_int = -1;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            SO_PEERPIDFD,
+            &mut fd as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// false once the process exited, its pid may belong to another by then
+pub fn pidfd_alive(pidfd: &OwnedFd) -> bool {
+    unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd.as_raw_fd(),
+            0,
+            std::ptr::null::<libc::siginfo_t>(),
+            0,
+        ) == 0
+    }
+}
+
+/// connections handled at once, further ones are closed right away
+pub const MAX_CONNECTIONS: usize = 32;
+
+/// counts connections in progress, each holds a [`Slot`] until done
+#[derive(Debug, Default)]
+pub struct Slots(AtomicUsize);
+
+pub struct Slot<'a>(&'a AtomicUsize);
+
+impl Slots {
+    /// none if [`MAX_CONNECTIONS`] already taken
+    pub fn take(&self) -> Option<Slot<'_>> {
+        self.0
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_CONNECTIONS).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Slot(&self.0))
+    }
+
+    pub fn idle(&self) -> bool {
+        self.0.load(Ordering::Acquire) == 0
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// one json line each way, then the connection closes
+pub fn read_message<T: for<'de> Deserialize<'de>>(stream: &UnixStream) -> io::Result<T> {
+    let mut line = String::new();
+    BufReader::new(stream.take(1 << 20)).read_line(&mut line)?;
+    serde_json::from_str(line.as_str()).map_err(io::Error::other)
+}
+
+pub fn write_message<T: Serialize>(mut stream: &UnixStream, msg: &T) -> io::Result<()> {
+    let mut buf = serde_json::to_vec(msg).map_err(io::Error::other)?;
+    buf.push(b'\n');
+    stream.write_all(&buf)?;
+    stream.shutdown(Shutdown::Write)
+}
+
+/// decrypt through a running `vaultix agent`
+pub struct AgentIdentity(pub PathBuf);
+
+impl AgentIdentity {
+    fn request(&self, req: &Request) -> io::Result<Response> {
+        let stream = UnixStream::connect(&self.0)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        write_message(&stream, req)?;
+        read_message(&stream)
+    }
+}
+
+impl Identity for AgentIdentity {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        self.unwrap_stanzas(std::slice::from_ref(stanza))
+    }
+
+    fn unwrap_stanzas(&self, stanzas: &[Stanza]) -> Option<Result<FileKey, DecryptError>> {
+        let req = Request::Unwrap(stanzas.iter().map(WireStanza::from).collect());
+        match self.request(&req) {
+            Ok(Response::FileKey(k)) => Some(
+                STANDARD
+                    .decode(k)
+                    .ok()
+                    .and_then(|k| <[u8; 16]>::try_from(k.as_slice()).ok())
+                    .map(|k| FileKey::new(Box::new(k)))
+                    .ok_or(DecryptError::KeyDecryptionFailed),
+            ),
+            Ok(Response::NoMatch) => None,
+            Ok(Response::Error(e)) => Some(Err(DecryptError::Io(io::Error::other(e)))),
+            Err(e) => Some(Err(DecryptError::Io(e))),
+        }
+    }
+}
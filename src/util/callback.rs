@@ -1,10 +1,123 @@
-use age::secrecy::{ExposeSecret, SecretString};
 use age::Callbacks;
+use age::secrecy::{ExposeSecret, SecretString};
+use eyre::{Context, eyre};
+use log::error;
 use pinentry::{ConfirmationDialog, PassphraseInput};
 use rpassword::prompt_password;
-use std::io;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    os::fd::BorrowedFd,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
 use subtle::ConstantTimeEq;
 
+#[derive(Debug, Clone)]
+pub enum PassphraseSource {
+    Fd(i32),
+    Env(String),
+    File(PathBuf),
+}
+
+/// how prompts are answered, set once from cli args
+#[derive(Debug, Clone, Default)]
+pub struct Interaction {
+    pub no_interactive: bool,
+    pub passphrase: Option<PassphraseSource>,
+}
+
+static INTERACTION: OnceLock<Interaction> = OnceLock::new();
+// fd could only be read once
+static FD_PASSPHRASE: OnceLock<Option<SecretString>> = OnceLock::new();
+/// answered once per identity, keyed by its file
+static PASSPHRASES: Mutex<Option<HashMap<String, SecretString>>> = Mutex::new(None);
+
+pub fn set_interaction(i: Interaction) {
+    let _ = INTERACTION.set(i);
+}
+
+fn interaction() -> &'static Interaction {
+    INTERACTION.get_or_init(Interaction::default)
+}
+
+impl PassphraseSource {
+    fn read(&self) -> eyre::Result<SecretString> {
+        let mut s = match self {
+            Self::Fd(fd) => {
+                if unsafe { libc::fcntl(*fd, libc::F_GETFD) } == -1 {
+                    return Err(io::Error::last_os_error())
+                        .wrap_err_with(|| eyre!("passphrase fd {} is not open", fd));
+                }
+                // SAFETY: checked open above, and never closed here, read
+                // through a duplicate left to the caller
+                let dup = unsafe { BorrowedFd::borrow_raw(*fd) }.try_clone_to_owned()?;
+                let mut buf = String::new();
+                File::from(dup)
+                    .read_to_string(&mut buf)
+                    .wrap_err_with(|| eyre!("read passphrase from fd {} error", fd))?;
+                buf
+            }
+            Self::Env(name) => std::env::var(name)
+                .wrap_err_with(|| eyre!("read passphrase from env {} error", name))?,
+            Self::File(p) => std::fs::read_to_string(p)
+                .wrap_err_with(|| eyre!("read passphrase from {} error", p.display()))?,
+        };
+        // trailing newline of `echo` or editors
+        if s.ends_with('\n') {
+            s.pop();
+            if s.ends_with('\r') {
+                s.pop();
+            }
+        }
+        Ok(SecretString::from(s))
+    }
+}
+
+/**
+Passphrase from configured source, or prompt if interactive allowed
+
+Remembered by `key`, usually the identity file, so each identity is
+asked once and never handed another one's answer.
+*/
+pub fn request_passphrase(key: &str, description: &str) -> Option<SecretString> {
+    let mut cache = PASSPHRASES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(p) = cache.as_ref().and_then(|c| c.get(key)) {
+        return Some(p.clone());
+    }
+    let i = interaction();
+    let passphrase = match &i.passphrase {
+        Some(src @ PassphraseSource::Fd(_)) => FD_PASSPHRASE
+            .get_or_init(|| src.read().inspect_err(|e| error!("{:?}", e)).ok())
+            .clone(),
+        Some(src) => src.read().inspect_err(|e| error!("{:?}", e)).ok(),
+        None if i.no_interactive => {
+            error!(
+                "passphrase required but running non-interactive: {}",
+                description
+            );
+            None
+        }
+        None => read_secret(description, "input password:", None).ok(),
+    }?;
+    cache
+        .get_or_insert_default()
+        .insert(key.to_string(), passphrase.clone());
+    Some(passphrase)
+}
+
+/// new passphrase, asked twice when prompting
+pub fn request_new_passphrase(description: &str) -> Option<SecretString> {
+    let i = interaction();
+    if i.passphrase.is_some() || i.no_interactive {
+        return request_passphrase(description, description);
+    }
+    read_secret(description, "input password:", Some("confirm password:"))
+        .inspect_err(|e| error!("{}", e))
+        .ok()
+}
+
 #[derive(Clone, Copy)]
 pub struct UiCallbacks;
 
@@ -14,17 +127,31 @@ impl Callbacks for UiCallbacks {
     }
 
     fn confirm(&self, message: &str, yes_string: &str, no_string: Option<&str>) -> Option<bool> {
+        if interaction().no_interactive {
+            error!(
+                "declined plugin confirmation in non-interactive mode: {}",
+                message
+            );
+            return Some(false);
+        }
         confirm(message, yes_string, no_string).ok()
     }
 
     fn request_public_string(&self, description: &str) -> Option<String> {
+        if interaction().no_interactive {
+            error!(
+                "input required but running non-interactive: {}",
+                description
+            );
+            return None;
+        }
         let term = console::Term::stderr();
         term.write_str(description).ok()?;
         term.read_line().ok().filter(|s| !s.is_empty())
     }
 
     fn request_passphrase(&self, description: &str) -> Option<SecretString> {
-        read_secret(description, "input password:", None).ok()
+        request_passphrase(description, description)
     }
 }
 fn confirm(query: &str, ok: &str, cancel: Option<&str>) -> pinentry::Result<bool> {
@@ -103,3 +230,33 @@ pub fn read_secret(
         Ok(passphrase)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Write,
+        os::fd::{AsRawFd, FromRawFd},
+    };
+
+    #[test]
+    fn passphrase_fd_borrowed() {
+        assert!(PassphraseSource::Fd(-1).read().is_err());
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (read, mut write) = unsafe {
+            (
+                std::os::fd::OwnedFd::from_raw_fd(fds[0]),
+                File::from_raw_fd(fds[1]),
+            )
+        };
+        write.write_all(b"hunter2\n").unwrap();
+        drop(write);
+
+        let p = PassphraseSource::Fd(read.as_raw_fd()).read().unwrap();
+        assert_eq!(p.expose_secret(), "hunter2");
+        // still open, owned by caller
+        assert_ne!(unsafe { libc::fcntl(read.as_raw_fd(), libc::F_GETFD) }, -1);
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    ffi::{CStr, CString},
+    io, ptr,
+};
+
+use eyre::{Context, Result, bail, eyre};
+use libc::{c_long, c_ulong};
+
+use crate::profile::{KeyType, KeyringDest, KeyringKind};
+
+impl KeyType {
+    pub fn name(&self) -> &'static CStr {
+        match self {
+            Self::User => c"user",
+            Self::Logon => c"logon",
+        }
+    }
+}
+
+impl KeyringDest {
+    /// permission mask, `3f0b0000` or `0x3f0b0000`
+    pub fn perm_mask(&self) -> Result<u32> {
+        u32::from_str_radix(self.perm.trim_start_matches("0x"), 16)
+            .map_err(|_| eyre!("invalid key permission `{}`, expect hex mask", self.perm))
+    }
+
+    /// found without touching the system
+    pub fn check(&self) -> Vec<String> {
+        let mut errs = Vec::new();
+        if let Err(e) = self.perm_mask() {
+            errs.push(e.to_string());
+        }
+        // kernel refuses logon keys without a `service:` prefix
+        if self.r#type == KeyType::Logon
+            && !self.description.is_empty()
+            && self
+                .description
+                .split_once(':')
+                .is_none_or(|(p, _)| p.is_empty())
+        {
+            errs.push(format!(
+                "logon key description `{}` needs a `<service>:` prefix",
+                self.description
+            ));
+        }
+        if self.name.as_ref().is_some_and(|n| n.is_empty()) {
+            errs.push("keyring `name` is empty".into());
+        }
+        errs
+    }
+
+    /// `%user:vaultix:db`, how keyctl(1) refers a key by type and description
+    pub fn locator(&self) -> String {
+        format!(
+            "%{}:{}",
+            self.r#type.name().to_string_lossy(),
+            self.description
+        )
+    }
+}
+
+/// type, owner and permission the kernel reports of a key
+#[derive(Debug, PartialEq, Eq)]
+pub struct KeyInfo {
+    pub r#type: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub perm: u32,
+    pub description: String,
+}
+
+fn check(res: c_long) -> io::Result<c_long> {
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+fn keyctl(op: u32, args: [c_ulong; 4]) -> io::Result<c_long> {
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_keyctl,
+            op as c_ulong,
+            args[0],
+            args[1],
+            args[2],
+            args[3],
+        )
+    })
+}
+
+fn add_key(r#type: &CStr, description: &CStr, payload: &[u8], keyring: i32) -> io::Result<i32> {
+    let payload_ptr = if payload.is_empty() {
+        ptr::null()
+    } else {
+        payload.as_ptr()
+    };
+    check(unsafe {
+        libc::syscall(
+            libc::SYS_add_key,
+            r#type.as_ptr(),
+            description.as_ptr(),
+            payload_ptr,
+            payload.len(),
+            keyring as c_ulong,
+        )
+    })
+    .map(|s| s as i32)
+}
+
+fn search(keyring: i32, r#type: &CStr, description: &CStr) -> io::Result<Option<i32>> {
+    match keyctl(
+        libc::KEYCTL_SEARCH,
+        [
+            keyring as c_ulong,
+            r#type.as_ptr() as c_ulong,
+            description.as_ptr() as c_ulong,
+            0,
+        ],
+    ) {
+        Ok(s) => Ok(Some(s as i32)),
+        Err(e) if e.raw_os_error() == Some(libc::ENOKEY) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// variable length result of `KEYCTL_DESCRIBE` and `KEYCTL_READ`
+fn read_buf(op: u32, serial: i32) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    loop {
+        let len = keyctl(
+            op,
+            [
+                serial as c_ulong,
+                buf.as_mut_ptr() as c_ulong,
+                buf.len() as c_ulong,
+                0,
+            ],
+        )? as usize;
+        if len <= buf.len() {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+        buf.resize(len, 0);
+    }
+}
+
+fn cstring(s: &str) -> Result<CString> {
+    CString::new(s).map_err(|_| eyre!("`{}` contains nul byte", s))
+}
+
+/// keyring the key goes into, named one created if `create`
+fn target(dest: &KeyringDest, uid: u32, create: bool) -> Result<Option<i32>> {
+    let base = match dest.keyring {
+        KeyringKind::User => libc::KEY_SPEC_USER_KEYRING,
+        KeyringKind::Persistent => keyctl(
+            libc::KEYCTL_GET_PERSISTENT,
+            [
+                uid as c_ulong,
+                libc::KEY_SPEC_SESSION_KEYRING as c_ulong,
+                0,
+                0,
+            ],
+        )
+        .wrap_err_with(|| eyre!("get persistent keyring of uid {}", uid))?
+            as i32,
+    };
+    let Some(name) = &dest.name else {
+        return Ok(Some(base));
+    };
+    let name = cstring(name)?;
+    match search(base, c"keyring", &name)? {
+        Some(k) => Ok(Some(k)),
+        None if create => add_key(c"keyring", &name, &[], base)
+            .map(Some)
+            .wrap_err_with(|| eyre!("create keyring {:?}", name)),
+        None => Ok(None),
+    }
+}
+
+/**
+Add or update the key, then set its owner, expiry and permission
+
+Permission is set last, a mask without possessor `setattr` would forbid
+the others. Returns the key serial.
+*/
+pub fn add(dest: &KeyringDest, payload: &[u8], uid: u32, gid: u32) -> Result<i32> {
+    let keyring = target(dest, uid, true)?.expect("created if missing");
+    let perm = dest.perm_mask()?;
+    let serial = add_key(
+        dest.r#type.name(),
+        &cstring(&dest.description)?,
+        payload,
+        keyring,
+    )
+    .wrap_err_with(|| eyre!("add key {}", dest.locator()))?;
+    keyctl(
+        libc::KEYCTL_CHOWN,
+        [serial as c_ulong, uid as c_ulong, gid as c_ulong, 0],
+    )
+    .wrap_err_with(|| eyre!("chown key {}", dest.locator()))?;
+    if dest.timeout > 0 {
+        keyctl(
+            libc::KEYCTL_SET_TIMEOUT,
+            [serial as c_ulong, dest.timeout as c_ulong, 0, 0],
+        )
+        .wrap_err_with(|| eyre!("set timeout of key {}", dest.locator()))?;
+    }
+    keyctl(
+        libc::KEYCTL_SETPERM,
+        [serial as c_ulong, perm as c_ulong, 0, 0],
+    )
+    .wrap_err_with(|| eyre!("set permission of key {}", dest.locator()))?;
+    Ok(serial)
+}
+
+/// serial of the key in its keyring, none if gone or expired
+pub fn find(dest: &KeyringDest, uid: u32) -> Result<Option<i32>> {
+    let Some(keyring) = target(dest, uid, false)? else {
+        return Ok(None);
+    };
+    match search(keyring, dest.r#type.name(), &cstring(&dest.description)?) {
+        Err(e)
+            if [Some(libc::EKEYEXPIRED), Some(libc::EKEYREVOKED)].contains(&e.raw_os_error()) =>
+        {
+            Ok(None)
+        }
+        r => r.wrap_err_with(|| eyre!("search key {}", dest.locator())),
+    }
+}
+
+pub fn describe(serial: i32) -> Result<KeyInfo> {
+    let raw = read_buf(libc::KEYCTL_DESCRIBE, serial).wrap_err("describe key")?;
+    let raw = String::from_utf8_lossy(&raw);
+    let raw = raw.trim_end_matches('\0');
+    // type;uid;gid;perm;description, description may contain `;`
+    let [r#type, uid, gid, perm, description] = raw.splitn(5, ';').collect::<Vec<_>>()[..] else {
+        bail!("unknown key description `{}`", raw);
+    };
+    let num = |s: &str, radix| {
+        u32::from_str_radix(s, radix).map_err(|_| eyre!("unknown key description `{}`", raw))
+    };
+    Ok(KeyInfo {
+        r#type: r#type.to_string(),
+        uid: num(uid, 10)?,
+        gid: num(gid, 10)?,
+        perm: num(perm, 16)?,
+        description: description.to_string(),
+    })
+}
+
+/// payload of a `user` key, `logon` keys are never readable
+pub fn read(serial: i32) -> Result<Vec<u8>> {
+    read_buf(libc::KEYCTL_READ, serial).wrap_err("read key")
+}
+
+/// unlink from every keyring at once, false if already gone
+pub fn invalidate(serial: i32) -> Result<bool> {
+    match keyctl(libc::KEYCTL_INVALIDATE, [serial as c_ulong, 0, 0, 0]) {
+        Ok(_) => Ok(true),
+        Err(e)
+            if [
+                Some(libc::ENOKEY),
+                Some(libc::EKEYEXPIRED),
+                Some(libc::EKEYREVOKED),
+            ]
+            .contains(&e.raw_os_error()) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e).wrap_err_with(|| eyre!("invalidate key {}", serial)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dest_checks() {
+        let dest = KeyringDest {
+            r#type: KeyType::Logon,
+            keyring: KeyringKind::User,
+            name: None,
+            description: "cryptsetup:root".into(),
+            perm: "0x3f010000".into(),
+            timeout: 0,
+        };
+        assert!(dest.check().is_empty());
+        assert_eq!(dest.perm_mask().unwrap(), 0x3f010000);
+        assert_eq!(dest.locator(), "%logon:cryptsetup:root");
+
+        let bad = [
+            KeyringDest {
+                description: "root".into(),
+                ..dest.clone()
+            },
+            KeyringDest {
+                perm: "rw".into(),
+                ..dest.clone()
+            },
+            KeyringDest {
+                name: Some(String::new()),
+                ..dest.clone()
+            },
+        ];
+        bad.iter()
+            .for_each(|d| assert_eq!(d.check().len(), 1, "{:?}", d));
+    }
+}
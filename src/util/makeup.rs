@@ -1,7 +1,6 @@
 use std::{
     iter,
     path::PathBuf,
-    rc::Rc,
     str::FromStr,
     sync::{Arc, Mutex},
 };
@@ -28,9 +27,12 @@ use super::{
 use eyre::{Context, ContextCompat, Result, eyre};
 
 impl<'a> RencInstance<'a> {
-    pub fn makeup(self, ctx_agenc: &RencCtx<'a, AgeEnc>, ident: Box<dyn Identity>) -> Result<()> {
-        let key: Rc<dyn Identity> = Rc::from(ident);
-
+    /// written cache paths, and errors of those failed
+    pub fn makeup(
+        self,
+        ctx_agenc: &RencCtx<'a, AgeEnc>,
+        ident: &dyn Identity,
+    ) -> Result<(Vec<PathBuf>, Vec<eyre::Report>)> {
         let material = &self.inner().into_read_only();
 
         info!("re-ecrypting...");
@@ -45,8 +47,6 @@ impl<'a> RencInstance<'a> {
         );
         std::thread::scope(|s| {
             material.iter().for_each(|(h, v)| {
-                let key = key.clone();
-
                 let sec_plain_map: Arc<DashMap<&profile::Secret, SecBuf<Plain>>> =
                     Arc::new(DashMap::new());
 
@@ -65,7 +65,7 @@ impl<'a> RencInstance<'a> {
                                         .inner_ref()
                                         .get(k)
                                         .wrap_err_with(|| eyre!("encrypted buf not found"))
-                                        .and_then(|pl| pl.decrypt(key.as_ref()))
+                                        .and_then(|pl| pl.decrypt(ident))
                                     {
                                         sec_plain_map.insert(*k, o);
                                     }
@@ -101,6 +101,21 @@ impl<'a> RencInstance<'a> {
                     return;
                 };
 
+                let extra_recips: Vec<Box<dyn Recipient + Send>> = match h
+                    .extra_recips()
+                    .iter()
+                    .map(|i| RawRecip::from(i.trim().to_string()).try_into())
+                    .try_collect()
+                {
+                    Ok(o) => o,
+                    Err(e) => {
+                        res.lock()
+                            .expect("doesn't matter now")
+                            .push(Err(e.wrap_err(eyre!("parse extra recipient fail"))));
+                        return;
+                    }
+                };
+
                 s.spawn(move || {
                     for (_, inrepo_path) in v.iter() {
                         if let Err(e) = inrepo_path
@@ -138,7 +153,10 @@ impl<'a> RencInstance<'a> {
                             .and_then(|s| sec_plain_map.get(*s))
                             .expect("must have");
 
-                        let ctt = match buf.clone().encrypt(iter::once(recip.as_ref())) {
+                        let ctt = match buf.clone().encrypt(
+                            iter::once(recip.as_ref())
+                                .chain(extra_recips.iter().map(|i| i.as_ref())),
+                        ) {
                             Ok(o) => o,
                             e @ Err(_) => {
                                 res.lock()
@@ -151,7 +169,8 @@ impl<'a> RencInstance<'a> {
                         if target_file.write_all(ctt.inner().as_bytes()).is_err() {
                             res.lock()
                                 .expect("doesn't matter now")
-                                .push(Err(eyre!("write cache file failed")))
+                                .push(Err(eyre!("write cache file failed")));
+                            continue;
                         };
                         res.lock()
                             .expect("thread work end")
@@ -163,14 +182,12 @@ impl<'a> RencInstance<'a> {
 
         info!("finished");
 
-        let last_res = res.lock().expect("never");
+        let last_res = std::mem::take(&mut *res.lock().expect("never"));
 
-        last_res.iter().for_each(|i| {
-            if let Err(e) = i {
-                error!("{}", e);
-            }
-        });
+        let (written, failed): (Vec<_>, Vec<_>) = last_res.into_iter().partition(|i| i.is_ok());
+        let failed: Vec<eyre::Report> = failed.into_iter().filter_map(|i| i.err()).collect();
+        failed.iter().for_each(|e| error!("{}", e));
 
-        Ok(())
+        Ok((written.into_iter().filter_map(|i| i.ok()).collect(), failed))
     }
 }
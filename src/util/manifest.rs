@@ -0,0 +1,181 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context, Result, eyre};
+use serde::{Deserialize, Serialize};
+
+use crate::profile::DeployFactor;
+
+/// lives in each generation dir, root only
+pub const MANIFEST: &str = ".manifest.json";
+
+/// context of fingerprint key derived from host key
+const FINGERPRINT_CONTEXT: &str = "vaultix 2024-10 deploy manifest fingerprint";
+
+/// what one deploy placed, for tearing it down later
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub generation: usize,
+    pub phase: String,
+    /// symlink pointing to the generation dir
+    pub link: PathBuf,
+    /// profiles deployed, store paths for those from nixos module
+    #[serde(default)]
+    pub profiles: Vec<PathBuf>,
+    pub items: Vec<ManifestItem>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemKind {
+    Secret,
+    Template,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ManifestItem {
+    pub id: String,
+    pub kind: ItemKind,
+    /// in generation dir, a custom `path`, or `%type:description` of a key
+    pub path: PathBuf,
+    pub mode: String,
+    pub owner: String,
+    pub group: String,
+    /// keyed blake3 of content, see [`fingerprint_key`]
+    pub fingerprint: String,
+    /// serial of kernel key, for those added to keyring
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<i32>,
+}
+
+impl ManifestItem {
+    pub fn new(
+        kind: ItemKind,
+        id: &str,
+        item: &dyn DeployFactor,
+        path: PathBuf,
+        key: &[u8; 32],
+        content: &[u8],
+    ) -> Self {
+        Self {
+            id: id.to_string(),
+            kind,
+            path,
+            mode: item.mode().clone(),
+            owner: item.owner().clone(),
+            group: item.group().clone(),
+            fingerprint: fingerprint(key, content),
+            serial: None,
+        }
+    }
+}
+
+/**
+Derive fingerprint key from host private key
+
+Plain hashes of short secrets are easy to brute force, keyed by something
+only root on the host reads they reveal nothing.
+*/
+pub fn fingerprint_key(host_key: &[u8]) -> [u8; 32] {
+    blake3::derive_key(FINGERPRINT_CONTEXT, host_key)
+}
+
+pub fn fingerprint(key: &[u8; 32], content: &[u8]) -> String {
+    blake3::keyed_hash(key, content).to_hex().to_string()
+}
+
+impl Manifest {
+    pub fn read(generation_dir: impl AsRef<Path>) -> Result<Option<Self>> {
+        let p = generation_dir.as_ref().join(MANIFEST);
+        match fs::read_to_string(&p) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).wrap_err_with(|| eyre!("read manifest error")),
+            Ok(s) => serde_json::from_str(s.as_str())
+                .map(Some)
+                .wrap_err_with(|| eyre!("parse manifest fail: {}", p.display())),
+        }
+    }
+
+    pub fn write(&self, generation_dir: impl AsRef<Path>) -> Result<()> {
+        let p = generation_dir.as_ref().join(MANIFEST);
+        let content = serde_json::to_string_pretty(self)?;
+        OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .mode(0o600)
+            .open(&p)
+            .and_then(|mut f| f.write_all(content.as_bytes()))
+            .wrap_err_with(|| eyre!("write manifest error: {}", p.display()))
+    }
+}
+
+/// generation dirs under mount point, those named by number
+pub fn generations(mount_point: impl AsRef<Path>) -> Result<Vec<(usize, PathBuf)>> {
+    let dir = match fs::read_dir(mount_point.as_ref()) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        r => r.wrap_err_with(|| eyre!("read mountpoint error"))?,
+    };
+    let mut gens: Vec<(usize, PathBuf)> = dir
+        .filter_map(|en| {
+            let en = en.ok()?;
+            let generation = en.file_name().to_str()?.parse().ok()?;
+            Some((generation, en.path()))
+        })
+        .collect();
+    gens.sort();
+    Ok(gens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("3")).unwrap();
+        fs::create_dir_all(dir.join("10")).unwrap();
+        fs::write(dir.join(".pending-owners.json"), "[]").unwrap();
+
+        let m = Manifest {
+            generation: 3,
+            phase: "default".into(),
+            link: "/run/vaultix".into(),
+            profiles: vec!["/nix/store/xxx-secret-meta-web".into()],
+            items: vec![ManifestItem {
+                id: "a".into(),
+                kind: ItemKind::Secret,
+                path: dir.join("3/a"),
+                mode: "0400".into(),
+                owner: "root".into(),
+                group: "root".into(),
+                fingerprint: fingerprint(&fingerprint_key(b"host key"), b"content"),
+                serial: None,
+            }],
+        };
+        assert_eq!(Manifest::read(dir.join("3")).unwrap(), None);
+        m.write(dir.join("3")).unwrap();
+        assert_eq!(Manifest::read(dir.join("3")).unwrap(), Some(m));
+
+        let gens: Vec<usize> = generations(dir).unwrap().into_iter().map(|g| g.0).collect();
+        assert_eq!(gens, [3, 10]);
+    }
+
+    #[test]
+    fn fingerprint_keyed() {
+        let a = fingerprint_key(b"host a");
+        let b = fingerprint_key(b"host b");
+        assert_eq!(fingerprint(&a, b"hunter2"), fingerprint(&a, b"hunter2"));
+        assert_ne!(fingerprint(&a, b"hunter2"), fingerprint(&b, b"hunter2"));
+        assert_ne!(
+            fingerprint(&a, b"hunter2"),
+            blake3::hash(b"hunter2").to_hex().to_string()
+        );
+    }
+}
@@ -0,0 +1,143 @@
+use std::{collections::BTreeMap, fs, io::ErrorKind, path::Path};
+
+use age::Recipient;
+use eyre::{Context, Result, eyre};
+use serde::{Deserialize, Serialize};
+
+use crate::{parser::recipient::RawRecip, profile::Profile};
+
+/// host identifier - public key, kept in repo
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HostRegistry(pub BTreeMap<String, String>);
+
+#[derive(Debug)]
+pub struct Mismatch<'a> {
+    pub id: &'a str,
+    pub profile: String,
+    pub registry: Option<String>,
+}
+
+fn validate(id: &str, pubkey: &str) -> Result<()> {
+    TryInto::<Box<dyn Recipient + Send>>::try_into(RawRecip::from(pubkey.trim().to_string()))
+        .map(|_| ())
+        .wrap_err_with(|| eyre!("invalid pubkey of host `{}` in registry", id))
+}
+
+impl HostRegistry {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let reg: Self = match fs::read_to_string(path.as_ref()) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).wrap_err_with(|| eyre!("read host registry error")),
+            Ok(s) => serde_json::from_str(s.as_str())
+                .wrap_err_with(|| eyre!("parse host registry fail: {}", path.as_ref().display()))?,
+        };
+        reg.0.iter().try_for_each(|(k, v)| validate(k, v))?;
+        Ok(reg)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(path.as_ref(), content + "\n")
+            .wrap_err_with(|| eyre!("write host registry error: {}", path.as_ref().display()))
+    }
+
+    /// return the replaced key if fingerprint changed
+    pub fn insert(&mut self, id: &str, pubkey: String) -> Result<Option<String>> {
+        validate(id, &pubkey)?;
+        let fp = RawRecip::from(pubkey.clone()).fingerprint();
+        Ok(self
+            .0
+            .insert(id.to_string(), pubkey)
+            .filter(|old| RawRecip::from(old.clone()).fingerprint() != fp))
+    }
+
+    /// profiles whose `hostPubkey` not the same key as registered
+    pub fn mismatches<'a>(&self, profiles: &'a [Profile]) -> Vec<Mismatch<'a>> {
+        profiles
+            .iter()
+            .filter_map(|p| {
+                let id = p.host_identifier();
+                let profile = RawRecip::from(p.host_pubkey().to_string()).fingerprint();
+                let registry = self
+                    .0
+                    .get(id)
+                    .map(|k| RawRecip::from(k.clone()).fingerprint());
+                (registry.as_ref() != Some(&profile)).then_some(Mismatch {
+                    id,
+                    profile,
+                    registry,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use age::x25519;
+
+    use super::*;
+    use crate::profile::Format;
+
+    const WEB: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIK8cZJNPowphvGWo27VxpyOGBlMp8373F8k1pqO5uU0o";
+
+    fn profile(host: &str, pubkey: &str) -> Profile {
+        let v = serde_json::json!({
+            "version": 2,
+            "settings": { "hostIdentifier": host, "hostPubkey": pubkey },
+            "secrets": {},
+        });
+        Profile::parse_as(v.to_string().as_str(), Format::Json).unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("hosts.json");
+        assert!(HostRegistry::load(&path).unwrap().0.is_empty());
+
+        let db = x25519::Identity::generate().to_public().to_string();
+        let mut reg = HostRegistry::default();
+        assert_eq!(reg.insert("web", WEB.to_string()).unwrap(), None);
+        assert_eq!(reg.insert("db", db.clone()).unwrap(), None);
+        // same key with a comment is no change
+        assert_eq!(
+            reg.insert("web", format!("{} root@web", WEB)).unwrap(),
+            None
+        );
+        assert!(reg.insert("bad", "ssh-ed25519".into()).is_err());
+        reg.save(&path).unwrap();
+
+        let loaded = HostRegistry::load(&path).unwrap();
+        assert_eq!(loaded.0.len(), 2);
+        assert_eq!(loaded.0["db"], db);
+
+        fs::write(&path, r#"{ "web": "not a key" }"#).unwrap();
+        assert!(HostRegistry::load(&path).is_err());
+    }
+
+    #[test]
+    fn detect_mismatch() {
+        let other = x25519::Identity::generate().to_public().to_string();
+        let mut reg = HostRegistry::default();
+        reg.insert("web", WEB.to_string()).unwrap();
+        assert_eq!(
+            reg.insert("web", other.clone()).unwrap().as_deref(),
+            Some(WEB)
+        );
+        reg.insert("web", WEB.to_string()).unwrap();
+
+        let profiles = [
+            profile("web", &format!("{} root@web", WEB)),
+            profile("db", WEB),
+            profile("web", &other),
+        ];
+        let found: Vec<_> = reg
+            .mismatches(&profiles)
+            .into_iter()
+            .map(|m| (m.id, m.registry.is_some()))
+            .collect();
+        assert_eq!(found, [("db", false), ("web", true)]);
+    }
+}
@@ -0,0 +1,52 @@
+use std::{fs, io::ErrorKind, path::Path};
+
+use eyre::{Context, Result, eyre};
+use serde::{Deserialize, Serialize};
+
+use crate::parser::recipient::RawRecip;
+
+/// lives in host cache dir, so it goes into store with `cacheInStore`
+pub const ROTATION_RECORD: &str = ".rotation.json";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KeyRecord {
+    pub pubkey: String,
+    pub fingerprint: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RotationRecord {
+    pub host: String,
+    pub old: KeyRecord,
+    pub new: KeyRecord,
+}
+
+impl From<&RawRecip> for KeyRecord {
+    fn from(value: &RawRecip) -> Self {
+        Self {
+            pubkey: value.as_ref().to_string(),
+            fingerprint: value.fingerprint(),
+        }
+    }
+}
+
+impl RotationRecord {
+    pub fn read(host_cache_dir: impl AsRef<Path>) -> Result<Option<Self>> {
+        let p = host_cache_dir.as_ref().join(ROTATION_RECORD);
+        match fs::read_to_string(&p) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).wrap_err_with(|| eyre!("read rotation record error")),
+            Ok(s) => serde_json::from_str(s.as_str())
+                .map(Some)
+                .wrap_err_with(|| eyre!("parse rotation record fail: {}", p.display())),
+        }
+    }
+
+    pub fn write(&self, host_cache_dir: impl AsRef<Path>) -> Result<()> {
+        fs::create_dir_all(host_cache_dir.as_ref())
+            .wrap_err_with(|| eyre!("create host cache dir in repo failed"))?;
+        let content = serde_json::to_string_pretty(self)?;
+        fs::write(host_cache_dir.as_ref().join(ROTATION_RECORD), content)
+            .wrap_err_with(|| eyre!("write rotation record error"))
+    }
+}
@@ -1,6 +1,7 @@
-use std::fs::{OpenOptions, Permissions};
-use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::PathBuf;
 use std::{io::Read, iter, marker::PhantomData};
 
@@ -29,15 +30,23 @@ impl<T> SecBuf<T> {
         self.buf
     }
 
-    pub fn hash_with(&self, host_ssh_recip: &str) -> blake3::Hash {
+    /// extra recipients are order insensitive, and without them the
+    /// hash is the same as before they're introduced
+    pub fn hash_with(&self, host_ssh_recip: &str, extra_recips: &[String]) -> blake3::Hash {
         let mut hasher = blake3::Hasher::new();
         hasher.update(&self.buf);
         hasher.update(host_ssh_recip.as_bytes());
+        let mut extra: Vec<&str> = extra_recips.iter().map(|i| i.trim()).collect();
+        extra.sort_unstable();
+        extra.iter().for_each(|i| {
+            hasher.update(b"\n");
+            hasher.update(i.as_bytes());
+        });
         hasher.finalize()
     }
 }
 
-use eyre::Result;
+use eyre::{Context, Result};
 impl<T> SecBuf<T> {
     pub fn buf_ref(&self) -> &Vec<u8> {
         self.buf.as_ref()
@@ -116,30 +125,63 @@ impl SecBuf<Plain> {
         Ok(SecBuf::new(enc_content))
     }
 
+    /**
+    Write to a temporary file beside `dst` then rename it into place
+
+    Owner, group and ACL are looked up before anything is opened, and the
+    file is chowned before written, so `dst` is never seen half written or
+    of unintended owner. With `defer`, an owner unknown yet is returned
+    instead of failing. Names are looked up in `ids` first.
+    */
     pub fn deploy_to_fs(
         &self,
         item: impl crate::profile::DeployFactor,
         dst: PathBuf,
-    ) -> Result<()> {
-        let mut the_file = {
-            let mode = crate::parser::parse_octal_str(item.mode())
-                .map_err(|e| eyre!("parse octal permission err: {}", e))?;
-            let permissions = Permissions::from_mode(mode);
-
-            let file = OpenOptions::new()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .open(dst)?;
-
-            file.set_permissions(permissions)?;
-
-            set_owner_group::set_owner_and_group(&file, item.owner(), item.group())?;
+        defer: bool,
+        ids: &set_owner_group::IdMap,
+    ) -> Result<Option<set_owner_group::PendingOwner>> {
+        let mode = crate::parser::parse_octal_str(item.mode())
+            .map_err(|e| eyre!("parse octal permission err: {}", e))?;
+        let ownership = set_owner_group::resolve_ownership(
+            &dst,
+            item.owner(),
+            item.group(),
+            item.acl(),
+            defer,
+            ids,
+        )?;
+
+        let name = dst
+            .file_name()
+            .ok_or_else(|| eyre!("no file name in {}", dst.display()))?;
+        let mut tmp_name = OsString::from(".");
+        tmp_name.push(name);
+        tmp_name.push(".vaultix-tmp");
+        let tmp = dst.with_file_name(tmp_name);
+        // left by an interrupted deploy, never anything but our own output
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).wrap_err_with(|| eyre!("remove stale temp file {}", tmp.display()));
+            }
+            _ => (),
+        }
 
-            file
-        };
-        the_file.write_all(self.buf_ref())?;
-        Ok(())
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp)
+            .wrap_err_with(|| eyre!("create temp file {}", tmp.display()))?;
+        file.set_permissions(Permissions::from_mode(mode))
+            .map_err(eyre::Report::from)
+            .and_then(|_| ownership.apply(&file))
+            .and_then(|_| Ok(file.write_all(self.buf_ref())?))
+            .and_then(|_| Ok(fs::rename(&tmp, &dst)?))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&tmp);
+            })
+            .wrap_err_with(|| eyre!("write {}", dst.display()))?;
+        Ok(ownership.pending)
     }
 }
 
@@ -0,0 +1,178 @@
+use std::fs;
+
+use eyre::{Context, Result, bail, eyre};
+use log::info;
+use sys_mount::{Mount, MountFlags, SupportedFilesystems};
+
+use crate::profile::{FsType, SecretsFs};
+
+const KNOWN_FLAGS: [(&str, MountFlags); 6] = [
+    ("nosuid", MountFlags::NOSUID),
+    ("nodev", MountFlags::NODEV),
+    ("noexec", MountFlags::NOEXEC),
+    ("relatime", MountFlags::RELATIME),
+    ("noatime", MountFlags::NOATIME),
+    ("nodiratime", MountFlags::NODIRATIME),
+];
+
+const REQUIRED_FLAGS: [&str; 2] = ["nosuid", "nodev"];
+
+/// tmpfs `noswap` landed in this version
+const NOSWAP_SINCE: (u32, u32) = (6, 4);
+
+impl FsType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ramfs => "ramfs",
+            Self::Tmpfs => "tmpfs",
+        }
+    }
+}
+
+fn valid_size(size: &str) -> bool {
+    let digits = size.trim_end_matches(['k', 'K', 'm', 'M', 'g', 'G', '%']);
+    size.len() - digits.len() <= 1
+        && digits.parse::<u64>().is_ok_and(|n| n > 0)
+        && !digits.starts_with('+')
+}
+
+/// major and minor of running kernel, `6.6.30` -> (6, 6)
+fn kernel_version() -> Result<(u32, u32)> {
+    let release =
+        fs::read_to_string("/proc/sys/kernel/osrelease").wrap_err("read kernel release error")?;
+    let mut nums = release
+        .trim()
+        .split(|c: char| !c.is_ascii_digit())
+        .map(str::parse::<u32>);
+    match (nums.next(), nums.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => Ok((major, minor)),
+        _ => Err(eyre!("unknown kernel release `{}`", release.trim())),
+    }
+}
+
+impl SecretsFs {
+    /// unsafe or meaningless combinations, found without touching the system
+    pub fn check(&self) -> Vec<String> {
+        let mut errs: Vec<String> = self
+            .flags
+            .iter()
+            .filter(|f| !KNOWN_FLAGS.iter().any(|(n, _)| n == f))
+            .map(|f| format!("unknown mount flag `{}`", f))
+            .collect();
+        REQUIRED_FLAGS
+            .iter()
+            .filter(|r| !self.flags.iter().any(|f| f == *r))
+            .for_each(|r| errs.push(format!("mount flag `{}` required", r)));
+
+        match self.r#type {
+            FsType::Ramfs => {
+                if self.size.is_some() {
+                    errs.push("ramfs has no size limit, `size` only applies to tmpfs".into());
+                }
+            }
+            FsType::Tmpfs => {
+                if !self.noswap {
+                    errs.push("tmpfs without `noswap` may write secrets to swap".into());
+                }
+                if let Some(s) = self.size.as_ref().filter(|s| !valid_size(s)) {
+                    errs.push(format!("invalid tmpfs size `{}`", s));
+                }
+            }
+        }
+        errs
+    }
+
+    fn mount_flags(&self) -> MountFlags {
+        KNOWN_FLAGS
+            .iter()
+            .filter(|(n, _)| self.flags.iter().any(|f| f == n))
+            .fold(MountFlags::empty(), |acc, (_, f)| acc | *f)
+    }
+
+    fn data(&self) -> String {
+        let mut data = vec!["mode=751".to_string()];
+        if self.r#type == FsType::Tmpfs {
+            data.extend(self.size.as_ref().map(|s| format!("size={}", s)));
+            data.push("noswap".into());
+        }
+        data.join(",")
+    }
+
+    /// probe kernel support, refuse what could leak secrets, then mount
+    pub fn mount(&self, target: &str) -> Result<()> {
+        if let Some(e) = self.check().first() {
+            bail!("refuse to mount secrets filesystem: {}", e);
+        }
+        let name = self.r#type.name();
+        if !SupportedFilesystems::new()?.is_supported(name) {
+            bail!(
+                "{} not supported by kernel, refuse to extract secrets",
+                name
+            );
+        }
+        if self.r#type == FsType::Tmpfs && kernel_version()? < NOSWAP_SINCE {
+            bail!(
+                "tmpfs `noswap` needs linux {}.{} or later, refuse to mount swappable secrets",
+                NOSWAP_SINCE.0,
+                NOSWAP_SINCE.1
+            );
+        }
+
+        let data = self.data();
+        info!("mounting {} on {} with {}", name, target, data);
+        Mount::builder()
+            .fstype(name)
+            .flags(self.mount_flags())
+            .data(&data)
+            .mount(String::default(), target)
+            .map(|_| ())
+            .wrap_err_with(|| eyre!("mount {} error", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsafe_combinations() {
+        assert!(SecretsFs::default().check().is_empty());
+
+        let tmpfs = SecretsFs {
+            r#type: FsType::Tmpfs,
+            size: Some("16M".into()),
+            ..Default::default()
+        };
+        assert!(tmpfs.check().is_empty());
+        assert_eq!(tmpfs.data(), "mode=751,size=16M,noswap");
+
+        let bad = [
+            SecretsFs {
+                noswap: false,
+                ..tmpfs.clone()
+            },
+            SecretsFs {
+                size: Some("0".into()),
+                ..tmpfs.clone()
+            },
+            SecretsFs {
+                size: Some("16MB".into()),
+                ..tmpfs.clone()
+            },
+            SecretsFs {
+                size: Some("16M".into()),
+                ..Default::default()
+            },
+            SecretsFs {
+                flags: vec!["nosuid".into()],
+                ..Default::default()
+            },
+            SecretsFs {
+                flags: vec!["nosuid".into(), "nodev".into(), "suid".into()],
+                ..Default::default()
+            },
+        ];
+        bad.iter()
+            .for_each(|fs| assert_eq!(fs.check().len(), 1, "{:?}", fs));
+    }
+}
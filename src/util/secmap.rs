@@ -8,6 +8,7 @@ use std::{
 
 use crate::{
     cmd::renc::CompleteProfile,
+    parser::glob_match,
     profile::{self, Secret},
     util::secbuf::AgeEnc,
 };
@@ -18,7 +19,10 @@ use eyre::{Result, eyre};
 use log::debug;
 use std::marker::PhantomData;
 
-use super::secbuf::{Decryptable, HostEnc, SecBuf};
+use super::{
+    rotation::ROTATION_RECORD,
+    secbuf::{Decryptable, HostEnc, SecBuf},
+};
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct SecPath<P: AsRef<Path>, T> {
@@ -66,9 +70,9 @@ where
         Ok(buffer)
     }
 }
-// identifier, recip
+// identifier, recip, extra recips
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
-pub struct HostInfo<'a>(&'a str, &'a str);
+pub struct HostInfo<'a>(&'a str, &'a str, &'a [String]);
 impl<'a> HostInfo<'a> {
     pub fn id(&self) -> &'a str {
         self.0
@@ -76,6 +80,9 @@ impl<'a> HostInfo<'a> {
     pub fn recip(&self) -> &str {
         self.1
     }
+    pub fn extra_recips(&self) -> &'a [String] {
+        self.2
+    }
 }
 #[derive(Debug, Clone)]
 pub struct RencBuilder<'a>(
@@ -164,7 +171,11 @@ impl<'a> RencBuilder<'a> {
             .inner_ref()
             .iter()
             .map(|x| {
-                let host_info = HostInfo(x.host_identifier(), x.host_pubkey());
+                let host_info = HostInfo(
+                    x.host_identifier(),
+                    x.host_pubkey(),
+                    x.settings.extra_recipients.as_slice(),
+                );
                 let s_ps: HashMap<&Secret, SecPathBuf<InStore>> = x
                     .secrets
                     .values()
@@ -187,6 +198,19 @@ impl<'a> RencBuilder<'a> {
         Self(b)
     }
 
+    /// keep only hosts whose identifier matches any of the glob patterns,
+    /// empty patterns keep all
+    pub fn retain_hosts(mut self, patterns: &[String]) -> Self {
+        if patterns.is_empty() {
+            return self;
+        }
+        self.0.retain(|_, hosts| {
+            hosts.retain(|h| patterns.iter().any(|p| glob_match(p, h.id())));
+            !hosts.is_empty()
+        });
+        self
+    }
+
     pub fn build_inrepo(
         self,
         ctx: &RencCtx<'a, AgeEnc>,
@@ -194,7 +218,11 @@ impl<'a> RencBuilder<'a> {
     ) -> RencData<'a, InRepo> {
         RencData::<'_, InRepo>(self.0.iter().fold(HashMap::new(), |mut acc, ((x, _), z)| {
             z.iter().for_each(|h| {
-                let hash = ctx.0.get(x).expect("never").hash_with(h.recip());
+                let hash = ctx
+                    .0
+                    .get(x)
+                    .expect("never")
+                    .hash_with(h.recip(), h.extra_recips());
                 let in_repo = {
                     let mut p: PathBuf = cache_dir.clone();
                     p.push(h.0);
@@ -227,7 +255,7 @@ impl<'a> RencData<'a, InStore> {
                     .inner_ref()
                     .get(x)
                     .expect("must have")
-                    .hash_with(y.recip())
+                    .hash_with(y.recip(), y.extra_recips())
                     .to_string();
 
                 dir.push(sec_hash);
@@ -251,7 +279,8 @@ impl<'a> RencData<'a, InStore> {
 }
 
 impl<'a> RencData<'a, InRepo> {
-    pub fn clean_outdated(&self, cache_dir: PathBuf) -> Result<()> {
+    pub fn clean_outdated(&self, cache_dir: PathBuf) -> Result<Vec<PathBuf>> {
+        let mut removed = Vec::new();
         self.inner_ref().keys().map(|(_, v)| v).try_for_each(|h| {
             let host_cache_dir = {
                 let mut c = cache_dir.clone();
@@ -271,6 +300,9 @@ impl<'a> RencData<'a, InRepo> {
             let tobe_clean = dir.filter_map(|entry| {
                 let entry = entry.ok()?;
                 let path = entry.path();
+                if entry.file_name() == ROTATION_RECORD {
+                    return None;
+                }
                 if path.is_file() && !self.have(&path) {
                     Some(path)
                 } else {
@@ -280,10 +312,12 @@ impl<'a> RencData<'a, InRepo> {
 
             for p in tobe_clean {
                 debug!("cleaning old: {}", p.display());
-                std::fs::remove_file(p).with_context(|| eyre!("cleaning old renc file error"))?;
+                std::fs::remove_file(&p).with_context(|| eyre!("cleaning old renc file error"))?;
+                removed.push(p);
             }
             Ok(())
-        })
+        })?;
+        Ok(removed)
     }
 
     /// retain non exist path
@@ -0,0 +1,43 @@
+use std::{
+    io,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::agent::{read_message, write_message};
+
+pub const SOCKET_ENV: &str = "VAULTIX_SERVE_SOCK";
+
+const SOCKET: &str = "/run/vaultix-serve.sock";
+
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `$VAULTIX_SERVE_SOCK`, or `/run/vaultix-serve.sock`
+pub fn socket_path() -> PathBuf {
+    std::env::var(SOCKET_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| SOCKET.into())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    Get(String),
+}
+
+/// unknown and forbidden ids are both `Denied`, existence never leaks
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Secret(String),
+    Denied,
+    Error(String),
+}
+
+pub fn request(socket: &Path, req: &Request) -> io::Result<Response> {
+    let stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    write_message(&stream, req)?;
+    read_message(&stream)
+}
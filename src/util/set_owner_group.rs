@@ -1,27 +1,259 @@
-use eyre::{Result, eyre};
+use eyre::{Context, Result, eyre};
 use libc::{fchown, getgrnam, getpwnam};
-use log::warn;
-use std::{ffi::CString, fs::File, os::fd::AsRawFd};
-
-pub fn set_owner_and_group(file: &File, owner: &str, group: &str) -> Result<()> {
-    let fd = file.as_raw_fd();
-
-    let user_uid = get_uid_from_username(owner).or_else(|_| {
-        warn!("get uid of {} failed, fallback to uid 0", owner);
-        eyre::Ok(0)
-    })?;
-    let group_gid = get_gid_from_groupname(group).or_else(|_| {
-        warn!("get gid of {} failed, fallback to uid 0", group);
-        eyre::Ok(0)
-    })?;
-    let result = unsafe { fchown(fd, user_uid, group_gid) };
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::acl;
+use crate::error::Error;
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+/// written under decrypted mount point, lists items chowned to root for now
+pub const PENDING_OWNERS: &str = ".pending-owners.json";
+
+/// names to ids consulted before this system, for staging roots
+#[derive(Debug, Default, Clone)]
+pub struct IdMap {
+    pub users: HashMap<String, u32>,
+    pub groups: HashMap<String, u32>,
+    /// deploying unprivileged into a staging root, chown refused is warned
+    pub staging: bool,
+}
+
+impl IdMap {
+    /// mapped uid first, then [`resolve_uid`]
+    pub fn uid(&self, user: &str) -> Result<u32> {
+        self.users
+            .get(user)
+            .copied()
+            .map_or_else(|| resolve_uid(user), Ok)
+    }
+
+    /// mapped gid first, then [`resolve_gid`]
+    pub fn gid(&self, group: &str) -> Result<u32> {
+        self.groups
+            .get(group)
+            .copied()
+            .map_or_else(|| resolve_gid(group), Ok)
+    }
+}
+
+/// owner of a deployed item that couldn't be resolved yet
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingOwner {
+    pub path: PathBuf,
+    pub owner: String,
+    pub group: String,
+    #[serde(default)]
+    pub acl: Vec<String>,
+}
+
+impl PendingOwner {
+    fn describe(&self, e: &eyre::Report) -> String {
+        format!(
+            "{} ({}:{}): {:#}",
+            self.path.display(),
+            self.owner,
+            self.group,
+            e
+        )
+    }
+}
+
+/// ids and ACL entries looked up from names
+struct Resolved {
+    uid: u32,
+    gid: u32,
+    acl: Vec<acl::Entry>,
+    staging: bool,
+}
+
+fn resolve(owner: &str, group: &str, acl: &[String], ids: &IdMap) -> Result<Resolved> {
+    Ok(Resolved {
+        uid: ids.uid(owner)?,
+        gid: ids.gid(group)?,
+        acl: acl::resolve(acl, ids)?,
+        staging: ids.staging,
+    })
+}
+
+fn chown(file: &File, uid: u32, gid: u32) -> io::Result<()> {
+    let result = unsafe { fchown(file.as_raw_fd(), uid, gid) };
 
     if result == -1 {
-        eyre::bail!("set permission failed");
+        return Err(io::Error::last_os_error());
     }
     Ok(())
 }
 
+fn apply(file: &File, r: &Resolved) -> Result<()> {
+    match chown(file, r.uid, r.gid) {
+        // manifest still names the intended owner, effective once in image
+        Err(e) if r.staging && e.raw_os_error() == Some(libc::EPERM) => {
+            warn!("chown to {}:{} refused, kept as is", r.uid, r.gid)
+        }
+        res => res.wrap_err("set permission failed")?,
+    }
+    if !r.acl.is_empty() {
+        acl::set_acl(file, &r.acl)?;
+    }
+    Ok(())
+}
+
+/// ids to chown to, and the intended owner if they're a fallback
+pub struct Ownership {
+    resolved: Resolved,
+    pub pending: Option<PendingOwner>,
+}
+
+impl Ownership {
+    /// chown to resolved ids, then add ACL entries
+    pub fn apply(&self, file: &File) -> Result<()> {
+        apply(file, &self.resolved)
+    }
+}
+
+/**
+Look up owner, group and ACL names before anything is written
+
+With `defer`, unknown names fallback to root without ACL and the intended
+ones are kept for [`fix_pending_owners`]. Otherwise unknown names are
+[`Error::UnresolvedOwner`].
+*/
+pub fn resolve_ownership(
+    path: &Path,
+    owner: &str,
+    group: &str,
+    acl: &[String],
+    defer: bool,
+    ids: &IdMap,
+) -> Result<Ownership> {
+    let pending = PendingOwner {
+        path: path.to_path_buf(),
+        owner: owner.to_string(),
+        group: group.to_string(),
+        acl: acl.to_vec(),
+    };
+    match resolve(owner, group, acl, ids) {
+        Ok(resolved) => Ok(Ownership {
+            resolved,
+            pending: None,
+        }),
+        Err(e) if defer => {
+            warn!("owner of {} deferred: {:#}", path.display(), e);
+            Ok(Ownership {
+                resolved: Resolved {
+                    uid: ids.uid(owner).unwrap_or(0),
+                    gid: ids.gid(group).unwrap_or(0),
+                    acl: Vec::new(),
+                    staging: ids.staging,
+                },
+                pending: Some(pending),
+            })
+        }
+        Err(e) => Err(Error::UnresolvedOwner(vec![pending.describe(&e)]).into()),
+    }
+}
+
+/// chown to owner and group then add ACL entries, see [`resolve_ownership`]
+pub fn set_owner_and_group(
+    file: &File,
+    path: &Path,
+    owner: &str,
+    group: &str,
+    acl: &[String],
+    defer: bool,
+    ids: &IdMap,
+) -> Result<Option<PendingOwner>> {
+    let ownership = resolve_ownership(path, owner, group, acl, defer, ids)?;
+    ownership.apply(file)?;
+    Ok(ownership.pending)
+}
+
+fn read_pending(record: &Path) -> Result<Vec<PendingOwner>> {
+    match fs::read(record) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).wrap_err_with(|| eyre!("read {}", record.display())),
+        Ok(c) => serde_json::from_slice(&c).wrap_err_with(|| eyre!("parse {}", record.display())),
+    }
+}
+
+fn write_pending(record: &Path, pending: &[PendingOwner]) -> Result<()> {
+    if pending.is_empty() {
+        return match fs::remove_file(record) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).wrap_err_with(|| eyre!("remove {}", record.display()))
+            }
+            _ => Ok(()),
+        };
+    }
+    OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .mode(0o600)
+        .open(record)
+        .and_then(|mut f| f.write_all(&serde_json::to_vec(pending)?))
+        .wrap_err_with(|| eyre!("write {}", record.display()))
+}
+
+/// append to the record under mount point
+pub fn record_pending_owners(mount_point: &Path, pending: Vec<PendingOwner>) -> Result<()> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let record = mount_point.join(PENDING_OWNERS);
+    let mut all = read_pending(&record)?;
+    all.retain(|p| !pending.iter().any(|n| n.path == p.path));
+    all.extend(pending);
+    write_pending(&record, &all)
+}
+
+/**
+Chown recorded items and set their ACL now that users exist
+
+Items already gone with their generation are dropped. Returns those still
+unresolvable, which stay recorded.
+*/
+pub fn fix_pending_owners(mount_point: &Path, ids: &IdMap) -> Result<Vec<PendingOwner>> {
+    let record = mount_point.join(PENDING_OWNERS);
+    let (fixed, remain): (Vec<_>, Vec<_>) = read_pending(&record)?
+        .into_iter()
+        .filter(|p| p.path.exists())
+        .partition(|p| {
+            resolve(&p.owner, &p.group, &p.acl, ids)
+                .and_then(|r| apply(&File::open(&p.path)?, &r))
+                .inspect_err(|e| warn!("fix owner of {}", p.describe(e)))
+                .is_ok()
+        });
+    fixed.iter().for_each(|p| {
+        info!(
+            "owner of {} set to {}:{}",
+            p.path.display(),
+            p.owner,
+            p.group
+        )
+    });
+    write_pending(&record, &remain)?;
+    Ok(remain)
+}
+
+/// numeric uid as is, otherwise looked up by name
+pub fn resolve_uid(user: &str) -> Result<u32> {
+    user.parse().or_else(|_| get_uid_from_username(user))
+}
+
+/// numeric gid as is, otherwise looked up by name
+pub fn resolve_gid(group: &str) -> Result<u32> {
+    group.parse().or_else(|_| get_gid_from_groupname(group))
+}
+
 fn get_uid_from_username(username: &str) -> Result<u32> {
     let c_username = CString::new(username).map_err(|_| eyre!("Invalid username: {}", username))?;
 
@@ -46,3 +278,38 @@ fn get_gid_from_groupname(groupname: &str) -> Result<u32> {
         Ok((*gr).gr_gid)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_record_roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let gone = PendingOwner {
+            path: dir.join("gone"),
+            owner: "nobody-here".into(),
+            group: "nobody-here".into(),
+            acl: vec!["user:0:r".into()],
+        };
+        let stuck = PendingOwner {
+            path: dir.join("stuck"),
+            ..gone.clone()
+        };
+        File::create(&stuck.path).unwrap();
+
+        record_pending_owners(dir, vec![gone.clone()]).unwrap();
+        record_pending_owners(dir, vec![stuck.clone(), gone.clone()]).unwrap();
+        assert_eq!(read_pending(&dir.join(PENDING_OWNERS)).unwrap().len(), 2);
+
+        assert_eq!(
+            fix_pending_owners(dir, &IdMap::default()).unwrap(),
+            vec![stuck.clone()]
+        );
+        assert_eq!(
+            read_pending(&dir.join(PENDING_OWNERS)).unwrap(),
+            vec![stuck]
+        );
+    }
+}